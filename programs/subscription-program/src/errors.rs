@@ -4,4 +4,82 @@ use anchor_lang::prelude::*;
 pub enum SubscriptionErrors {
     #[msg("Subscription is not ready to be credited")]
     SubscriptionNotReady,
+    #[msg("Plan cannot move to the requested state")]
+    InvalidPlanStateTransition,
+    #[msg("Fee cannot be more than 10000 basis points")]
+    InvalidFee,
+    #[msg("Arithmetic overflow")]
+    MathOverflow,
+    #[msg("Batch accounts must be subscription and subscriber token account pairs for the plan")]
+    InvalidBatchAccounts,
+    #[msg("Coupon needs a discount of at most 10000 basis points and at least one term")]
+    InvalidCoupon,
+    #[msg("Coupon has expired")]
+    CouponExpired,
+    #[msg("Coupon has no redemptions left")]
+    CouponExhausted,
+    #[msg("Quantity must be at least one")]
+    InvalidQuantity,
+    #[msg("A gift has to cover at least one term")]
+    InvalidGiftTerms,
+    #[msg("Closing a gifted subscription needs its gift vault")]
+    MissingGiftVault,
+    #[msg("Prepay discount cannot be more than 10000 basis points")]
+    InvalidPrepayDiscount,
+    #[msg("Terms can't be prepaid during a trial")]
+    PrepaidTrial,
+    #[msg("Plan doesn't allow pausing")]
+    PausingDisabled,
+    #[msg("No pauses left this year")]
+    PauseLimitReached,
+    #[msg("Only the subscriber can resume before the longest allowed pause is over")]
+    PauseNotOver,
+    #[msg("Account is already on the latest layout")]
+    AlreadyMigrated,
+    #[msg("Plan code must be 1 to 64 printable ascii characters without spaces")]
+    InvalidPlanCode,
+    #[msg("Plan name can be at most 64 bytes and its metadata uri at most 200")]
+    InvalidPlanMetadata,
+    #[msg("Subscription is not in a state that allows this")]
+    InvalidSubscriptionState,
+    #[msg("Signer is not allowed to do this")]
+    Unauthorized,
+    #[msg("Token account or mint doesn't match the plan's mint")]
+    MintMismatch,
+    #[msg("Token account is not owned by the expected wallet")]
+    InvalidTokenAccountOwner,
+    #[msg("Token account is not the one the subscription pays from")]
+    TokenAccountMismatch,
+    #[msg("Account is not the subscription's payer authority")]
+    PayerAuthorityMismatch,
+    #[msg("Delegation doesn't cover the charge, the subscriber has to refresh it")]
+    InsufficientAllowance,
+    #[msg("Plan is not accepting this")]
+    PlanInactive,
+    #[msg("Plan still has live subscriptions")]
+    PlanHasLiveSubscriptions,
+    #[msg("Subscription can only change to another plan from the same owner and mint")]
+    InvalidPlanChange,
+    #[msg("Not available for gifted subscriptions")]
+    GiftedSubscription,
+    #[msg("Term must be at least one second")]
+    InvalidTerm,
+    #[msg("Price must be more than zero unless the plan charges for usage")]
+    InvalidPrice,
+    #[msg("Subscribing to a plan with a trial needs the subscriber's trial record")]
+    MissingTrialRecord,
+    #[msg("Plan durations can be at most 100 years")]
+    InvalidDuration,
+    #[msg("Coupon has already been redeemed for this subscriber")]
+    CouponAlreadyRedeemed,
+    #[msg("Subscribing with a coupon needs the subscriber's coupon redemption record")]
+    MissingCouponRedemption,
+    #[msg("Plan has to be migrated before its subscriptions")]
+    PlanNotMigrated,
+    #[msg("Subscription belongs to a different plan")]
+    PlanMismatch,
+    #[msg("Subscriptions from before the migration may still be in a paid term")]
+    UnmigratedTermsNotOver,
+    #[msg("Coupon code must be 1 to 32 printable ascii characters without spaces")]
+    InvalidCouponCode,
 }
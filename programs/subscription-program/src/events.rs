@@ -0,0 +1,329 @@
+use anchor_lang::prelude::*;
+
+use crate::instructions::{
+    create_coupon::Discount, create_plan::PlanState, create_subscription::SubscriptionState,
+    merchant_cancel_subscription::MerchantCancelMode,
+};
+
+#[event]
+pub struct PlanCreated {
+    pub plan: Pubkey,
+    pub owner: Pubkey,
+    pub code: String,
+    pub name: String,
+    pub metadata_uri: String,
+    pub token_mint: Pubkey,
+    pub price: u64,
+    pub term_in_seconds: u64,
+    pub trial_seconds: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct PlanUpdated {
+    pub plan: Pubkey,
+    pub version: u32,
+    pub price: u64,
+    pub term_in_seconds: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct PlanStateChanged {
+    pub plan: Pubkey,
+    pub state: PlanState,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct PlanClosed {
+    pub plan: Pubkey,
+    // escrow left over when the plan closed, paid to the owner;
+    pub drained: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct PlanMigrated {
+    pub plan: Pubkey,
+    pub from_version: u8,
+    pub to_version: u8,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct UnmigratedSubscriptionsReleased {
+    pub plan: Pubkey,
+    // subscriptions the plan stopped waiting on, closed before the migration;
+    pub released: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct CouponCreated {
+    pub plan: Pubkey,
+    pub coupon: Pubkey,
+    pub code: String,
+    pub discount: Discount,
+    pub duration_terms: u32,
+    pub max_redemptions: u32,
+    pub expires_at: i64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct SubscriptionCreated {
+    pub plan: Pubkey,
+    pub subscription: Pubkey,
+    pub subscriber: Pubkey,
+    pub payer_authority: Pubkey,
+    pub plan_version: u32,
+    // escrowed for the first term, zero during a trial;
+    pub amount: u64,
+    pub trial: bool,
+    pub prepaid_terms: u32,
+    pub next_term_date: i64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct SubscriptionGifted {
+    pub plan: Pubkey,
+    pub subscription: Pubkey,
+    pub subscriber: Pubkey,
+    pub gifter: Pubkey,
+    pub terms: u32,
+    // escrowed for the first term;
+    pub amount: u64,
+    // set aside in the vault for the remaining terms;
+    pub vault_amount: u64,
+    pub next_term_date: i64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct CouponApplied {
+    pub plan: Pubkey,
+    pub coupon: Pubkey,
+    pub subscription: Pubkey,
+    pub subscriber: Pubkey,
+    // charged terms left at the discounted price;
+    pub discount_terms_remaining: u32,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct QuantityUpdated {
+    pub plan: Pubkey,
+    pub subscription: Pubkey,
+    pub subscriber: Pubkey,
+    pub quantity: u32,
+    // takes over from quantity at the next charge;
+    pub next_quantity: u32,
+    // prorated for seats added to the current term;
+    pub charged: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct UsageReported {
+    pub plan: Pubkey,
+    pub subscription: Pubkey,
+    pub units: u64,
+    // total for the current term so far;
+    pub usage_units: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct DelegationRefreshed {
+    pub plan: Pubkey,
+    pub subscription: Pubkey,
+    pub subscriber: Pubkey,
+    pub payer_authority: Pubkey,
+    pub delegation_amount: u64,
+    // stays AllowanceExhausted after a refused renewal until the next charge goes through;
+    pub state: SubscriptionState,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct SubscriptionCharged {
+    pub plan: Pubkey,
+    pub subscription: Pubkey,
+    pub subscriber: Pubkey,
+    pub term_index: u32,
+    pub plan_version: u32,
+    // escrowed for the term that just started;
+    pub amount: u64,
+    // billed for usage over the included units in the term that just ended;
+    pub usage_amount: u64,
+    // released from escrow for the term that just ended;
+    pub owner_amount: u64,
+    pub fee: u64,
+    pub keeper_reward: u64,
+    pub next_term_date: i64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct ChargeFailedPastDue {
+    pub plan: Pubkey,
+    pub subscription: Pubkey,
+    pub subscriber: Pubkey,
+    pub term_index: u32,
+    pub amount_due: u64,
+    pub retry_count: u8,
+    pub past_due_since: i64,
+    // released from escrow for the term that just ended, only on the first failure;
+    pub owner_amount: u64,
+    pub fee: u64,
+    pub keeper_reward: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct ChargeFailedAllowanceExhausted {
+    pub plan: Pubkey,
+    pub subscription: Pubkey,
+    pub subscriber: Pubkey,
+    pub term_index: u32,
+    pub amount_due: u64,
+    // what the delegation still covered, zero if it was revoked or approved elsewhere;
+    pub allowance: u64,
+    pub past_due_since: i64,
+    // released from escrow for the term that just ended, unless it was already past due;
+    pub owner_amount: u64,
+    pub fee: u64,
+    pub keeper_reward: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct SubscriptionLapsed {
+    pub plan: Pubkey,
+    pub subscription: Pubkey,
+    pub subscriber: Pubkey,
+    pub term_index: u32,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct SubscriptionCancelled {
+    pub plan: Pubkey,
+    pub subscription: Pubkey,
+    pub subscriber: Pubkey,
+    pub term_index: u32,
+    // the subscription stays active until then;
+    pub next_term_date: i64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct SubscriptionCancelledByMerchant {
+    pub plan: Pubkey,
+    pub subscription: Pubkey,
+    pub subscriber: Pubkey,
+    pub term_index: u32,
+    pub mode: MerchantCancelMode,
+    pub reason_code: u16,
+    // to the payer authority, including anything left in a gift's vault;
+    pub refund: u64,
+    // billed for usage over the included units in the term that was cut short;
+    pub usage_amount: u64,
+    pub owner_amount: u64,
+    pub fee: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct SubscriptionPaused {
+    pub plan: Pubkey,
+    pub subscription: Pubkey,
+    pub subscriber: Pubkey,
+    pub term_index: u32,
+    // including this one;
+    pub pauses_in_window: u8,
+    // after which anyone can resume it, zero for no limit;
+    pub max_pause_seconds: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct SubscriptionResumed {
+    pub plan: Pubkey,
+    pub subscription: Pubkey,
+    pub subscriber: Pubkey,
+    pub term_index: u32,
+    pub paused_seconds: i64,
+    // pushed back by however long the pause lasted;
+    pub next_term_date: i64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct SubscriptionUncancelled {
+    pub plan: Pubkey,
+    pub subscription: Pubkey,
+    pub subscriber: Pubkey,
+    pub term_index: u32,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct SubscriptionPlanChanged {
+    pub plan: Pubkey,
+    pub new_plan: Pubkey,
+    pub subscription: Pubkey,
+    pub new_subscription: Pubkey,
+    pub subscriber: Pubkey,
+    // the current term keeps its old price and the new plan applies from the next charge;
+    pub deferred: bool,
+    // unused part of the current term carried over to the new plan;
+    pub credit: u64,
+    pub charged: u64,
+    pub refund: u64,
+    // billed for usage over the included units on the old plan;
+    pub usage_amount: u64,
+    // released from escrow for the used part of the current term and its usage;
+    pub owner_amount: u64,
+    pub fee: u64,
+    pub next_term_date: i64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct SubscriptionCancellationFinalized {
+    pub plan: Pubkey,
+    pub subscription: Pubkey,
+    pub subscriber: Pubkey,
+    pub term_index: u32,
+    // released from escrow for the last term;
+    pub owner_amount: u64,
+    pub fee: u64,
+    pub keeper_reward: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct SubscriptionClosed {
+    pub plan: Pubkey,
+    pub subscription: Pubkey,
+    pub subscriber: Pubkey,
+    pub term_index: u32,
+    pub refund: u64,
+    // billed for usage over the included units in the term that was cut short;
+    pub usage_amount: u64,
+    pub owner_amount: u64,
+    pub fee: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct SubscriptionMigrated {
+    pub subscription: Pubkey,
+    pub plan: Pubkey,
+    pub from_version: u8,
+    pub to_version: u8,
+    pub timestamp: i64,
+}
@@ -0,0 +1,69 @@
+use anchor_lang::prelude::*;
+
+use crate::{events::CouponApplied, SubscriptionErrors};
+
+use super::{
+    create_coupon::{Coupon, CouponRedemption},
+    create_plan::Plan,
+    create_subscription::{Subscription, SubscriptionState},
+};
+
+pub fn handle_apply_coupon(ctx: Context<ApplyCouponParams>) -> Result<()> {
+    // the discount starts with the next charge and replaces whatever discount was left; each
+    // coupon can only be redeemed once per subscriber;
+    let subscription_account = &mut ctx.accounts.subscription_account;
+    let coupon_account = &mut ctx.accounts.coupon_account;
+    let current = Clock::get()?.unix_timestamp;
+    coupon_account.redeem(current)?;
+    ctx.accounts.coupon_redemption.record(
+        coupon_account.key(),
+        subscription_account.owner,
+        current,
+    )?;
+    subscription_account.discount = coupon_account.discount.clone();
+    subscription_account.discount_terms_remaining = coupon_account.duration_terms;
+    emit!(CouponApplied {
+        plan: ctx.accounts.plan_account.key(),
+        coupon: coupon_account.key(),
+        subscription: subscription_account.key(),
+        subscriber: subscription_account.owner,
+        discount_terms_remaining: subscription_account.discount_terms_remaining,
+        timestamp: current,
+    });
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct ApplyCouponParams<'info> {
+    #[account(
+        mut,
+        seeds = [b"subscription".as_ref(), subscription_account.owner.key().as_ref(), plan_account.key().as_ref()],
+        constraint = subscription_account.owner == payer.key() || subscription_account.payer_authority == payer.key() @ SubscriptionErrors::Unauthorized,
+        constraint = subscription_account.state != SubscriptionState::Lapsed @ SubscriptionErrors::InvalidSubscriptionState,
+        constraint = subscription_account.state != SubscriptionState::Cancelled @ SubscriptionErrors::InvalidSubscriptionState,
+        bump,
+    )]
+    pub subscription_account: Account<'info, Subscription>,
+    #[account(
+        seeds = [b"plan".as_ref(), plan_account.owner.key().as_ref(), plan_account.code_seed()],
+        bump,
+    )]
+    pub plan_account: Account<'info, Plan>,
+    #[account(
+        mut,
+        seeds = [b"coupon".as_ref(), plan_account.key().as_ref(), coupon_account.code.as_ref()],
+        bump,
+    )]
+    pub coupon_account: Account<'info, Coupon>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = CouponRedemption::SPACE,
+        seeds = [b"coupon_redemption".as_ref(), coupon_account.key().as_ref(), subscription_account.owner.as_ref()],
+        bump,
+    )]
+    pub coupon_redemption: Account<'info, CouponRedemption>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
@@ -1,5 +1,7 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::Token;
+use anchor_spl::token_interface::TokenInterface;
+
+use crate::{events::SubscriptionCancelled, SubscriptionErrors};
 
 use super::{
     create_plan::Plan,
@@ -9,7 +11,16 @@ use super::{
 pub fn handle_cancel_subscription(ctx: Context<CancelSubscriptionParams>) -> Result<()> {
     // cancel will cancel the subscription but let it finish out the current term;
     let subscription_account = &mut ctx.accounts.subscription_account;
-    subscription_account.state = SubscriptionState::PendingCancellation;
+    let plan_account = &mut ctx.accounts.plan_account;
+    subscription_account.transition(plan_account, SubscriptionState::PendingCancellation)?;
+    emit!(SubscriptionCancelled {
+        plan: plan_account.key(),
+        subscription: subscription_account.key(),
+        subscriber: subscription_account.owner,
+        term_index: subscription_account.term_index,
+        next_term_date: subscription_account.next_term_date,
+        timestamp: Clock::get()?.unix_timestamp,
+    });
     Ok(())
 }
 
@@ -18,19 +29,19 @@ pub struct CancelSubscriptionParams<'info> {
     #[account(
         mut,
         seeds = [b"subscription".as_ref(), subscription_account.owner.key().as_ref(), plan_account.key().as_ref()],
-        constraint = subscription_account.owner == payer.key(),
-        constraint = subscription_account.state == SubscriptionState::Active,
+        constraint = plan_account.cancel_authority.allows(&subscription_account, payer.key()) @ SubscriptionErrors::Unauthorized,
+        constraint = subscription_account.state == SubscriptionState::Active @ SubscriptionErrors::InvalidSubscriptionState,
         bump,
     )]
     pub subscription_account: Account<'info, Subscription>,
     #[account(
         mut,
-        seeds = [b"plan".as_ref(), plan_account.owner.key().as_ref(), plan_account.code.as_ref()],
+        seeds = [b"plan".as_ref(), plan_account.owner.key().as_ref(), plan_account.code_seed()],
         bump,
     )]
     pub plan_account: Account<'info, Plan>,
     #[account(mut)]
     pub payer: Signer<'info>,
     pub system_program: Program<'info, System>,
-    pub token_program: Program<'info, Token>,
+    pub token_program: Interface<'info, TokenInterface>,
 }
@@ -0,0 +1,283 @@
+use anchor_lang::{prelude::*, solana_program::program_option::COption};
+use anchor_spl::token_interface::{
+    approve, transfer_checked, Approve, Mint, TokenAccount, TokenInterface, TransferChecked,
+};
+
+use crate::{
+    events::SubscriptionPlanChanged,
+    math::{add_seconds, split_fee},
+    SubscriptionErrors,
+};
+
+use super::{
+    charge_subscription::PlanEscrow,
+    close_subscription::unused_term_credit,
+    create_plan::{Plan, PlanState},
+    create_subscription::{Subscription, SubscriptionState},
+    initialize_protocol_config::ProtocolConfig,
+};
+
+pub fn handle_change_plan(ctx: Context<ChangePlanParams>, data: ChangePlanData) -> Result<()> {
+    // the subscription address is derived from the plan, so changing plans moves it to a new account
+    // and carries the escrowed term over to the new plan's escrow; sponsored subscriptions can't
+    // change plans since the delegation has to be approved again by whoever pays, and neither can
+    // gifts since their vault belongs to the old subscription;
+    let subscription_account = &ctx.accounts.subscription_account;
+    let new_subscription_account = &mut ctx.accounts.new_subscription_account;
+    let plan_account = &mut ctx.accounts.plan_account;
+    let new_plan_account = &mut ctx.accounts.new_plan_account;
+    let plan_token_account = &ctx.accounts.plan_token_account;
+    let new_plan_token_account = &ctx.accounts.new_plan_token_account;
+    let payer_token_account = &mut ctx.accounts.payer_token_account;
+    let owner_token_account = &ctx.accounts.owner_token_account;
+    let deployer_token_account = &ctx.accounts.deployer_token_account;
+    let protocol_config = &ctx.accounts.protocol_config;
+    let mint_account = &ctx.accounts.mint_account;
+    let payer = &ctx.accounts.payer;
+    let token_program = &ctx.accounts.token_program;
+    let current = Clock::get()?.unix_timestamp;
+
+    // only downgrades can wait for the next term, upgrades always take effect now;
+    let deferred = data.defer_downgrade && new_plan_account.price < plan_account.price;
+
+    plan_account.track_state(Some(&subscription_account.state), None)?;
+    new_plan_account.track_state(None, Some(&SubscriptionState::Active))?;
+
+    let owner_key = plan_account.owner.key();
+    let seed = plan_account.code_seed().to_vec();
+    let new_seed = new_plan_account.code_seed().to_vec();
+    let (_pda, bump) = Pubkey::find_program_address(
+        &[b"plan".as_ref(), owner_key.as_ref(), seed.as_ref()],
+        ctx.program_id,
+    );
+    let (_pda, new_bump) = Pubkey::find_program_address(
+        &[b"plan".as_ref(), owner_key.as_ref(), new_seed.as_ref()],
+        ctx.program_id,
+    );
+    let escrow = PlanEscrow {
+        token_program: token_program.to_account_info(),
+        plan_account: plan_account.to_account_info(),
+        plan_token_account: plan_token_account.to_account_info(),
+        mint_account: mint_account.to_account_info(),
+        decimals: mint_account.decimals,
+        plan_seeds: &[b"plan".as_ref(), owner_key.as_ref(), seed.as_ref(), &[bump]],
+    };
+    let new_escrow = PlanEscrow {
+        token_program: token_program.to_account_info(),
+        plan_account: new_plan_account.to_account_info(),
+        plan_token_account: new_plan_token_account.to_account_info(),
+        mint_account: mint_account.to_account_info(),
+        decimals: mint_account.decimals,
+        plan_seeds: &[
+            b"plan".as_ref(),
+            owner_key.as_ref(),
+            new_seed.as_ref(),
+            &[new_bump],
+        ],
+    };
+    let before = new_escrow.balance()?;
+    let new_price = new_plan_account.price_for(subscription_account.quantity)?;
+
+    // usage on the old plan is billed now, the new subscription starts counting from zero;
+    let usage = escrow.collect_final_usage(
+        plan_account,
+        subscription_account,
+        payer_token_account,
+        ctx.program_id,
+    )?;
+    // the remaining allowance follows the subscription to its new address;
+    payer_token_account.reload()?;
+    let allowance = if payer_token_account.delegate == COption::Some(subscription_account.key()) {
+        payer_token_account.delegated_amount
+    } else {
+        0
+    };
+    let mut settled = usage;
+    let mut credit = 0;
+    let mut charged = 0;
+    let mut refund = 0;
+    if deferred {
+        // the current term was paid for at the old price and keeps running, the new price applies
+        // from the next charge;
+        escrow.transfer_from_escrow(
+            &new_plan_token_account.to_account_info(),
+            subscription_account.term_price,
+        )?;
+        new_subscription_account.next_term_date = subscription_account.next_term_date;
+        new_subscription_account.term_in_seconds = subscription_account.term_in_seconds;
+        let (unit_price, included_units) = subscription_account.usage_pricing(plan_account);
+        new_subscription_account.unit_price = unit_price;
+        new_subscription_account.included_units = included_units;
+    } else {
+        // the unused part of the current term is credited toward a new term on the new plan, the
+        // used part is settled the same way closing the subscription would;
+        credit = unused_term_credit(subscription_account, current, protocol_config.rounding)?;
+        settled = subscription_account
+            .term_price
+            .checked_sub(credit)
+            .and_then(|used| used.checked_add(usage))
+            .ok_or(SubscriptionErrors::MathOverflow)?;
+        if credit > 0 {
+            escrow.transfer_from_escrow(&new_plan_token_account.to_account_info(), credit)?;
+        }
+        let moved = new_escrow.received_since(before)?;
+        charged = new_price.saturating_sub(moved);
+        if charged > 0 {
+            let transfer_accounts = TransferChecked {
+                from: payer_token_account.to_account_info().clone(),
+                mint: mint_account.to_account_info().clone(),
+                to: new_plan_token_account.to_account_info().clone(),
+                authority: payer.to_account_info().clone(),
+            };
+            transfer_checked(
+                CpiContext::new(token_program.to_account_info().clone(), transfer_accounts),
+                charged,
+                mint_account.decimals,
+            )?;
+        }
+        // a credit bigger than the new price goes back to the subscriber;
+        refund = moved.saturating_sub(new_price);
+        if refund > 0 {
+            new_escrow.transfer_from_escrow(&payer_token_account.to_account_info(), refund)?;
+        }
+        new_subscription_account.next_term_date =
+            add_seconds(current, new_plan_account.term_in_seconds)?;
+        new_subscription_account.term_in_seconds = new_plan_account.term_in_seconds;
+        new_subscription_account.unit_price = new_plan_account.unit_price;
+        new_subscription_account.included_units = new_plan_account.included_units;
+    }
+    let (owner_amount, tax) = split_fee(settled, protocol_config.fee_bps)?;
+    escrow.transfer_from_escrow(&owner_token_account.to_account_info(), owner_amount)?;
+    escrow.transfer_from_escrow(&deployer_token_account.to_account_info(), tax)?;
+    new_subscription_account.plan_account = new_plan_account.key();
+    new_subscription_account.payer_token_account = payer_token_account.key();
+    new_subscription_account.owner = payer.key();
+    new_subscription_account.payer_authority = payer.key();
+    new_subscription_account.state = SubscriptionState::Active;
+    new_subscription_account.plan_version = new_plan_account.version;
+    new_subscription_account.layout_version = Subscription::LAYOUT_VERSION;
+    new_subscription_account.term_price = new_escrow.received_since(before)?;
+    new_subscription_account.retry_count = 0;
+    new_subscription_account.past_due_since = 0;
+    new_subscription_account.term_index = subscription_account.term_index;
+    new_subscription_account.quantity = subscription_account.quantity;
+    new_subscription_account.next_quantity = subscription_account.next_quantity;
+    new_subscription_account.delegated_allowance = allowance;
+    // coupons belong to a plan, so any discount on the old subscription is left behind;
+
+    let approve_accounts = Approve {
+        delegate: new_subscription_account.to_account_info().clone(),
+        to: payer_token_account.to_account_info().clone(),
+        authority: payer.to_account_info().clone(),
+    };
+    approve(
+        CpiContext::new(token_program.to_account_info().clone(), approve_accounts),
+        allowance,
+    )?;
+    plan_account.record_payout(owner_amount, tax)?;
+    plan_account.record_refund(refund)?;
+    emit!(SubscriptionPlanChanged {
+        plan: plan_account.key(),
+        new_plan: new_plan_account.key(),
+        subscription: subscription_account.key(),
+        new_subscription: new_subscription_account.key(),
+        subscriber: payer.key(),
+        deferred,
+        credit,
+        charged,
+        refund,
+        usage_amount: usage,
+        owner_amount,
+        fee: tax,
+        next_term_date: new_subscription_account.next_term_date,
+        timestamp: current,
+    });
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct ChangePlanParams<'info> {
+    #[account(
+        mut,
+        seeds = [b"subscription".as_ref(), subscription_account.owner.key().as_ref(), plan_account.key().as_ref()],
+        constraint = subscription_account.owner == payer.key() @ SubscriptionErrors::Unauthorized,
+        constraint = subscription_account.payer_authority == payer.key() @ SubscriptionErrors::Unauthorized,
+        constraint = !subscription_account.gifted @ SubscriptionErrors::GiftedSubscription,
+        constraint = subscription_account.state == SubscriptionState::Active @ SubscriptionErrors::InvalidSubscriptionState,
+        bump,
+        close = payer,
+    )]
+    pub subscription_account: Account<'info, Subscription>,
+    #[account(
+        init,
+        payer = payer,
+        space = Subscription::SPACE,
+        seeds = [b"subscription".as_ref(), payer.key().as_ref(), new_plan_account.key().as_ref()],
+        bump,
+    )]
+    pub new_subscription_account: Account<'info, Subscription>,
+    #[account(
+        mut,
+        seeds = [b"plan".as_ref(), plan_account.owner.key().as_ref(), plan_account.code_seed()],
+        bump,
+    )]
+    pub plan_account: Account<'info, Plan>,
+    #[account(
+        mut,
+        seeds = [b"plan".as_ref(), new_plan_account.owner.key().as_ref(), new_plan_account.code_seed()],
+        constraint = new_plan_account.key() != plan_account.key() @ SubscriptionErrors::InvalidPlanChange,
+        constraint = new_plan_account.owner == plan_account.owner @ SubscriptionErrors::InvalidPlanChange,
+        constraint = new_plan_account.token_mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = new_plan_account.state == PlanState::Active @ SubscriptionErrors::PlanInactive,
+        bump,
+    )]
+    pub new_plan_account: Account<'info, Plan>,
+    #[account(
+        mut,
+        constraint = plan_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = plan_token_account.owner == plan_account.key() @ SubscriptionErrors::InvalidTokenAccountOwner,
+    )]
+    pub plan_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = new_plan_token_account.mint == new_plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = new_plan_token_account.owner == new_plan_account.key() @ SubscriptionErrors::InvalidTokenAccountOwner,
+    )]
+    pub new_plan_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = payer_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = payer_token_account.owner == payer.key() @ SubscriptionErrors::InvalidTokenAccountOwner,
+        constraint = payer_token_account.key() == subscription_account.payer_token_account @ SubscriptionErrors::TokenAccountMismatch,
+    )]
+    pub payer_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = owner_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = owner_token_account.owner == plan_account.owner.key() @ SubscriptionErrors::InvalidTokenAccountOwner,
+    )]
+    pub owner_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = deployer_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = deployer_token_account.owner == protocol_config.fee_recipient @ SubscriptionErrors::InvalidTokenAccountOwner,
+    )]
+    pub deployer_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        seeds = [b"protocol_config".as_ref()],
+        bump,
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(address = plan_account.token_mint @ SubscriptionErrors::MintMismatch)]
+    pub mint_account: InterfaceAccount<'info, Mint>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Debug)]
+pub struct ChangePlanData {
+    // keep the current term on the old price when moving to a cheaper plan;
+    pub defer_downgrade: bool,
+}
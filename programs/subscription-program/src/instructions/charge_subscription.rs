@@ -1,12 +1,21 @@
-use anchor_lang::prelude::*;
-use anchor_spl::token::{transfer, Token, TokenAccount, Transfer};
-use solana_program::pubkey;
+use anchor_lang::{prelude::*, solana_program::program_option::COption};
+use anchor_spl::token_interface::{
+    revoke, transfer_checked, Mint, Revoke, TokenAccount, TokenInterface, TransferChecked,
+};
 
-use crate::SubscriptionErrors;
+use crate::{
+    events::{
+        ChargeFailedAllowanceExhausted, ChargeFailedPastDue, SubscriptionCancellationFinalized,
+        SubscriptionCharged, SubscriptionLapsed,
+    },
+    math::{add_seconds, fee_amount, split_fee},
+    SubscriptionErrors,
+};
 
 use super::{
-    create_plan::Plan,
+    create_plan::{KeeperReward, Plan, PlanState},
     create_subscription::{Subscription, SubscriptionState},
+    initialize_protocol_config::ProtocolConfig,
 };
 
 pub fn handle_charge_subscription(ctx: Context<ChargeSubscriptionParams>) -> Result<()> {
@@ -16,92 +25,580 @@ pub fn handle_charge_subscription(ctx: Context<ChargeSubscriptionParams>) -> Res
     let subscriber_token_account = &mut ctx.accounts.subscriber_token_account;
     let deployer_token_account = &mut ctx.accounts.deployer_token_account;
     let owner_token_account = &ctx.accounts.owner_token_account;
+    let keeper_token_account = &ctx.accounts.keeper_token_account;
+    let mint_account = &ctx.accounts.mint_account;
+    let protocol_config = &ctx.accounts.protocol_config;
+    let payer = &ctx.accounts.payer;
+    let token_program = &ctx.accounts.token_program;
 
     let current = Clock::get()?.unix_timestamp;
-    if current < subscription_account.next_term_date {
-        return Err(SubscriptionErrors::SubscriptionNotReady.into());
-    }
-    if subscriber_token_account.amount < plan_account.price {
-        subscription_account.state = SubscriptionState::PastDue;
-        return Ok(());
-    }
-    let transfer_accounts = Transfer {
-        from: subscriber_token_account.to_account_info().clone(),
-        to: plan_token_account.to_account_info().clone(),
-        authority: subscription_account.to_account_info().clone(),
+    let allowance = charge_allowance(
+        subscription_account,
+        subscription_account.key(),
+        subscriber_token_account,
+    );
+    // an exhausted allowance is only retried once it covers the charge;
+    let not_charged = if subscription_account.state == SubscriptionState::AllowanceExhausted {
+        SubscriptionErrors::InsufficientAllowance
+    } else {
+        SubscriptionErrors::SubscriptionNotReady
     };
+    let step = advance_subscription(
+        plan_account,
+        subscription_account,
+        current,
+        subscriber_token_account.amount,
+        allowance,
+    )?
+    .ok_or(not_charged)?;
 
-    let (_pda, subscription_bump) = Pubkey::find_program_address(
+    let plan_account_owner_key = plan_account.owner.key();
+    let (_pda, plan_bump) = Pubkey::find_program_address(
         &[
-            b"subscription".as_ref(),
-            subscription_account.owner.key().as_ref(),
-            plan_account.key().as_ref(),
+            b"plan".as_ref(),
+            plan_account_owner_key.as_ref(),
+            plan_account.code_seed(),
         ],
         ctx.program_id,
     );
-    let token_program = &ctx.accounts.token_program;
-    let cpi_program: AccountInfo<'_> = token_program.to_account_info();
-    transfer(
-        CpiContext::new_with_signer(
-            cpi_program.clone(),
-            transfer_accounts,
-            &[&[
-                b"subscription".as_ref(),
-                subscription_account.owner.key().as_ref(),
-                plan_account.key().as_ref(),
-                &[subscription_bump],
-            ]],
-        ),
-        plan_account.price,
-    )?;
-    let payout_accounts = Transfer {
-        from: plan_token_account.to_account_info().clone(),
-        to: owner_token_account.to_account_info().clone(),
-        authority: plan_account.to_account_info().clone(),
-    };
-    let tax = ((plan_account.price as f32) * 0.03) as u64;
-    let (_pda, plan_bump) = Pubkey::find_program_address(
-        &[
+    let escrow = PlanEscrow {
+        token_program: token_program.to_account_info(),
+        plan_account: plan_account.to_account_info(),
+        plan_token_account: plan_token_account.to_account_info(),
+        mint_account: mint_account.to_account_info(),
+        decimals: mint_account.decimals,
+        plan_seeds: &[
             b"plan".as_ref(),
-            plan_account.owner.key().as_ref(),
-            plan_account.code.as_ref(),
+            plan_account_owner_key.as_ref(),
+            plan_account.code_seed(),
+            &[plan_bump],
         ],
+    };
+    let received = escrow.collect(
+        &subscriber_token_account.to_account_info(),
+        &subscription_account.to_account_info(),
+        &subscription_account.owner,
         ctx.program_id,
+        step.collected,
+    )?;
+    let usage_received = escrow.collect(
+        &subscriber_token_account.to_account_info(),
+        &subscription_account.to_account_info(),
+        &subscription_account.owner,
+        ctx.program_id,
+        step.usage,
+    )?;
+    // payout the owner of the plan for the previous charge on the subscription and its usage;
+    let settlement = Settlement::for_step(
+        &step,
+        received,
+        usage_received,
+        protocol_config.fee_bps,
+        &plan_account.keeper_reward,
+    )?;
+    if step.outcome == ChargeOutcome::Renewed {
+        subscription_account.term_price = received - settlement.advanced;
+    }
+    emit_charge_event(
+        plan_account.key(),
+        subscription_account,
+        subscription_account.key(),
+        &step,
+        &settlement,
+        current,
     );
-    // payout the owner of the plan for the previous charge on the subscription;
-    transfer(
-        CpiContext::new_with_signer(
-            cpi_program.clone(),
-            payout_accounts,
-            &[&[
-                b"plan".as_ref(),
-                plan_account.owner.key().as_ref(),
-                plan_account.code.as_ref(),
-                &[plan_bump],
-            ]],
-        ),
-        plan_account.price - tax,
+    escrow.pay_out(
+        &settlement,
+        &owner_token_account.to_account_info(),
+        &deployer_token_account.to_account_info(),
+        &keeper_token_account.to_account_info(),
     )?;
-    let tax_accounts = Transfer {
-        from: plan_token_account.to_account_info().clone(),
-        to: deployer_token_account.to_account_info().clone(),
-        authority: plan_account.to_account_info().clone(),
+    plan_account.record_payout(settlement.owner_amount, settlement.tax)?;
+
+    // a gift still holds the unused terms in its vault, those are refunded by close_subscription;
+    if step.outcome == ChargeOutcome::Cancelled && !subscription_account.gifted {
+        // only the token account owner can revoke, so the delegation is revoked here when the
+        // payer authority cranks the cancellation and otherwise on close_subscription;
+        if payer.key() == subscription_account.payer_authority {
+            let revoke_accounts = Revoke {
+                source: subscriber_token_account.to_account_info().clone(),
+                authority: payer.to_account_info().clone(),
+            };
+            revoke(CpiContext::new(
+                token_program.to_account_info().clone(),
+                revoke_accounts,
+            ))?;
+        }
+        if let Some(payer_authority) = &ctx.accounts.payer_authority {
+            subscription_account.close(payer_authority.to_account_info())?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(PartialEq)]
+pub(crate) enum ChargeOutcome {
+    Renewed,
+    PastDue,
+    Lapsed,
+    Cancelled,
+    AllowanceExhausted,
+}
+
+// what a charge attempt decided, the caller is responsible for moving the tokens;
+pub(crate) struct ChargeStep {
+    pub outcome: ChargeOutcome,
+    // escrowed for the term that just ended and now owed to the owner;
+    pub settled: u64,
+    // to pull from the subscriber into escrow for the new term;
+    pub collected: u64,
+    // to pull from the subscriber for usage over the included units in the term that just ended,
+    // settled straight away;
+    pub usage: u64,
+    // what a renewal would have cost, for a failed charge;
+    pub due: u64,
+    // what the delegation still covered when the charge was attempted;
+    pub allowance: u64,
+}
+
+// how much the subscription can pull from the subscriber's token account; a gift's vault is owned
+// by the subscription itself, anything else goes through the delegation, which only counts while
+// it's still approved to this subscription;
+pub(crate) fn charge_allowance(
+    subscription_account: &Subscription,
+    subscription: Pubkey,
+    subscriber_token_account: &TokenAccount,
+) -> u64 {
+    if subscription_account.gifted {
+        return u64::MAX;
+    }
+    if subscriber_token_account.delegate != COption::Some(subscription) {
+        return 0;
+    }
+    subscriber_token_account.delegated_amount
+}
+
+// moves the subscription through its billing states at `current`, given what the subscriber holds
+// and what the delegation lets the subscription take; returns None when nothing is due yet;
+pub(crate) fn advance_subscription(
+    plan_account: &mut Plan,
+    subscription_account: &mut Subscription,
+    current: i64,
+    available: u64,
+    allowance: u64,
+) -> Result<Option<ChargeStep>> {
+    let mut step = ChargeStep {
+        outcome: ChargeOutcome::Renewed,
+        settled: 0,
+        collected: 0,
+        usage: 0,
+        due: 0,
+        allowance,
     };
-    transfer(
-        CpiContext::new_with_signer(
-            cpi_program.clone(),
-            tax_accounts,
-            &[&[
-                b"plan".as_ref(),
-                plan_account.owner.key().as_ref(),
-                plan_account.code.as_ref(),
-                &[plan_bump],
-            ]],
-        ),
-        tax,
+    // a pause can't outlast the plan's limit, one that has is charged as if it was resumed when
+    // the limit ran out;
+    if subscription_account.state == SubscriptionState::Paused {
+        match subscription_account.pause_ends(plan_account)? {
+            Some(ends) if current >= ends => subscription_account.resume(plan_account, ends)?,
+            _ => return Ok(None),
+        }
+    }
+    // nothing is due until both the current term and anything prepaid are over;
+    let due_at = subscription_account
+        .next_term_date
+        .max(subscription_account.paid_through);
+    if current < due_at {
+        return Ok(None);
+    }
+    // a sunset plan doesn't renew anything, its subscriptions end like a cancellation once their
+    // current term is over, whether or not that term was paid for;
+    if subscription_account.state == SubscriptionState::PendingCancellation
+        || plan_account.state == PlanState::Sunset
+    {
+        return finalize_cancellation(plan_account, subscription_account, step, available);
+    }
+    let is_exhausted = subscription_account.state == SubscriptionState::AllowanceExhausted;
+    let is_past_due = is_exhausted || subscription_account.state == SubscriptionState::PastDue;
+    if is_past_due {
+        // once the grace period is over the subscription lapses for good;
+        let grace_ends = add_seconds(
+            subscription_account.past_due_since,
+            plan_account.grace_seconds,
+        )?;
+        if current > grace_ends {
+            step.outcome = ChargeOutcome::Lapsed;
+            subscription_account.transition(plan_account, SubscriptionState::Lapsed)?;
+            return Ok(Some(step));
+        }
+        // a refreshed delegation is charged straight away rather than on the retry schedule;
+        let retry_wait = plan_account
+            .retry_interval_seconds
+            .checked_mul(subscription_account.retry_count as u64 + 1)
+            .ok_or(SubscriptionErrors::MathOverflow)?;
+        let next_retry = add_seconds(subscription_account.past_due_since, retry_wait)?;
+        if !is_exhausted && current < next_retry {
+            return Ok(None);
+        }
+    }
+
+    // the discount is only used up once the term is actually paid for;
+    let base_price = plan_account.price_for(subscription_account.next_quantity)?;
+    let price = subscription_account.discounted_price(base_price)?;
+    let usage = subscription_account.overage(plan_account)?;
+    step.due = price
+        .checked_add(usage)
+        .ok_or(SubscriptionErrors::MathOverflow)?;
+    // no one is going to top up a gift's vault, so a gift that can't cover the next term has run
+    // out and ends instead of going past due;
+    if subscription_account.gifted && available < step.due {
+        return finalize_cancellation(plan_account, subscription_account, step, available);
+    }
+    // usage stays on the subscription until it's paid so a retry bills it too; the allowance is
+    // checked first since no balance gets past a delegation that's run out;
+    if allowance < step.due {
+        if is_exhausted {
+            // still waiting on the subscriber to refresh it;
+            return Ok(None);
+        }
+        step.outcome = ChargeOutcome::AllowanceExhausted;
+        if !is_past_due {
+            step.settled = subscription_account.term_price;
+            subscription_account.term_price = 0;
+            subscription_account.past_due_since = current;
+            subscription_account.retry_count = 0;
+        }
+        subscription_account.transition(plan_account, SubscriptionState::AllowanceExhausted)?;
+        return Ok(Some(step));
+    }
+    if available < step.due {
+        step.outcome = ChargeOutcome::PastDue;
+        if is_past_due {
+            subscription_account.retry_count += 1;
+            if subscription_account.retry_count >= plan_account.retry_limit {
+                step.outcome = ChargeOutcome::Lapsed;
+                subscription_account.transition(plan_account, SubscriptionState::Lapsed)?;
+            } else {
+                subscription_account.transition(plan_account, SubscriptionState::PastDue)?;
+            }
+            return Ok(Some(step));
+        }
+        // the term that just ended was served, so the owner is paid for it now rather than on recovery;
+        step.settled = subscription_account.term_price;
+        subscription_account.term_price = 0;
+        subscription_account.transition(plan_account, SubscriptionState::PastDue)?;
+        subscription_account.past_due_since = current;
+        subscription_account.retry_count = 0;
+        return Ok(Some(step));
+    }
+    step.settled = subscription_account.term_price;
+    step.collected = subscription_account.take_discounted_price(base_price)?;
+    step.usage = usage;
+    subscription_account.usage_units = 0;
+    // the new term starts on the latest version of the plan;
+    subscription_account.term_index += 1;
+    subscription_account.next_term_date = add_seconds(
+        subscription_account.next_term_date,
+        plan_account.term_in_seconds,
     )?;
-    subscription_account.next_term_date += plan_account.term_in_seconds as i64;
-    Ok(())
+    subscription_account.plan_version = plan_account.version;
+    subscription_account.term_price = step.collected;
+    subscription_account.term_in_seconds = plan_account.term_in_seconds;
+    subscription_account.unit_price = plan_account.unit_price;
+    subscription_account.included_units = plan_account.included_units;
+    subscription_account.quantity = subscription_account.next_quantity;
+    subscription_account.transition(plan_account, SubscriptionState::Active)?;
+    subscription_account.past_due_since = 0;
+    subscription_account.retry_count = 0;
+    Ok(Some(step))
+}
+
+// the term is over: settle it and finalize the cancellation instead of renewing;
+fn finalize_cancellation(
+    plan_account: &mut Plan,
+    subscription_account: &mut Subscription,
+    mut step: ChargeStep,
+    available: u64,
+) -> Result<Option<ChargeStep>> {
+    step.outcome = ChargeOutcome::Cancelled;
+    step.settled = subscription_account.term_price;
+    // there's no later charge to retry usage that can't be paid now, so it's written off;
+    step.usage = subscription_account
+        .overage(plan_account)?
+        .min(available)
+        .min(step.allowance);
+    subscription_account.usage_units = 0;
+    subscription_account.term_price = 0;
+    subscription_account.transition(plan_account, SubscriptionState::Cancelled)?;
+    Ok(Some(step))
+}
+
+// how settled terms are split between the owner, the protocol and whoever cranked the charge;
+// the keeper is rewarded out of the owner's share, the protocol fee is untouched;
+#[derive(Clone, Default)]
+pub(crate) struct Settlement {
+    pub owner_amount: u64,
+    pub tax: u64,
+    pub reward: u64,
+    // the part of the reward the owner's share didn't cover, taken out of the term just collected
+    // so that term settles that much less;
+    pub advanced: u64,
+}
+
+impl Settlement {
+    // a renewal rewards the keeper on what it collected, which pays for a trial converting or a
+    // recovery as well even though they settle nothing; anything else on what it settles;
+    pub fn for_step(
+        step: &ChargeStep,
+        received: u64,
+        usage_received: u64,
+        fee_bps: u16,
+        keeper_reward: &KeeperReward,
+    ) -> Result<Self> {
+        let renewed = step.outcome == ChargeOutcome::Renewed;
+        let prepaid = if renewed { received } else { 0 };
+        let settled = step
+            .settled
+            .checked_add(usage_received)
+            .ok_or(SubscriptionErrors::MathOverflow)?;
+        let reward_base = if renewed {
+            prepaid
+                .checked_add(usage_received)
+                .ok_or(SubscriptionErrors::MathOverflow)?
+        } else {
+            settled
+        };
+        let (owner_amount, tax) = split_fee(settled, fee_bps)?;
+        let reward = match keeper_reward {
+            KeeperReward::None => 0,
+            KeeperReward::Fixed(amount) => *amount,
+            KeeperReward::Bps(bps) => fee_amount(reward_base, *bps)?,
+        }
+        .min(owner_amount.saturating_add(prepaid));
+        let advanced = reward.saturating_sub(owner_amount);
+        Ok(Settlement {
+            owner_amount: owner_amount - (reward - advanced),
+            tax,
+            reward,
+            advanced,
+        })
+    }
+
+    pub fn add(&mut self, other: &Settlement) -> Result<()> {
+        self.owner_amount = self
+            .owner_amount
+            .checked_add(other.owner_amount)
+            .ok_or(SubscriptionErrors::MathOverflow)?;
+        self.tax = self
+            .tax
+            .checked_add(other.tax)
+            .ok_or(SubscriptionErrors::MathOverflow)?;
+        self.reward = self
+            .reward
+            .checked_add(other.reward)
+            .ok_or(SubscriptionErrors::MathOverflow)?;
+        self.advanced = self
+            .advanced
+            .checked_add(other.advanced)
+            .ok_or(SubscriptionErrors::MathOverflow)?;
+        Ok(())
+    }
+}
+
+pub(crate) fn emit_charge_event(
+    plan: Pubkey,
+    subscription_account: &Subscription,
+    subscription: Pubkey,
+    step: &ChargeStep,
+    settlement: &Settlement,
+    timestamp: i64,
+) {
+    match step.outcome {
+        ChargeOutcome::Renewed => emit!(SubscriptionCharged {
+            plan,
+            subscription,
+            subscriber: subscription_account.owner,
+            term_index: subscription_account.term_index,
+            plan_version: subscription_account.plan_version,
+            amount: step.collected,
+            usage_amount: step.usage,
+            owner_amount: settlement.owner_amount,
+            fee: settlement.tax,
+            keeper_reward: settlement.reward,
+            next_term_date: subscription_account.next_term_date,
+            timestamp,
+        }),
+        ChargeOutcome::PastDue => emit!(ChargeFailedPastDue {
+            plan,
+            subscription,
+            subscriber: subscription_account.owner,
+            term_index: subscription_account.term_index,
+            amount_due: step.due,
+            retry_count: subscription_account.retry_count,
+            past_due_since: subscription_account.past_due_since,
+            owner_amount: settlement.owner_amount,
+            fee: settlement.tax,
+            keeper_reward: settlement.reward,
+            timestamp,
+        }),
+        ChargeOutcome::Lapsed => emit!(SubscriptionLapsed {
+            plan,
+            subscription,
+            subscriber: subscription_account.owner,
+            term_index: subscription_account.term_index,
+            timestamp,
+        }),
+        ChargeOutcome::AllowanceExhausted => emit!(ChargeFailedAllowanceExhausted {
+            plan,
+            subscription,
+            subscriber: subscription_account.owner,
+            term_index: subscription_account.term_index,
+            amount_due: step.due,
+            allowance: step.allowance,
+            past_due_since: subscription_account.past_due_since,
+            owner_amount: settlement.owner_amount,
+            fee: settlement.tax,
+            keeper_reward: settlement.reward,
+            timestamp,
+        }),
+        ChargeOutcome::Cancelled => emit!(SubscriptionCancellationFinalized {
+            plan,
+            subscription,
+            subscriber: subscription_account.owner,
+            term_index: subscription_account.term_index,
+            owner_amount: settlement.owner_amount,
+            fee: settlement.tax,
+            keeper_reward: settlement.reward,
+            timestamp,
+        }),
+    }
+}
+
+// the plan's token account, which holds what was charged for a term until it ends;
+// amounts are measured on what the escrow actually received, so mints with a transfer fee
+// extension never pay out more than they took in;
+pub(crate) struct PlanEscrow<'a, 'info> {
+    pub token_program: AccountInfo<'info>,
+    pub plan_account: AccountInfo<'info>,
+    pub plan_token_account: AccountInfo<'info>,
+    pub mint_account: AccountInfo<'info>,
+    pub decimals: u8,
+    pub plan_seeds: &'a [&'a [u8]],
+}
+
+impl<'a, 'info> PlanEscrow<'a, 'info> {
+    // pulls from the subscriber using the delegation approved to the subscription account and
+    // returns what actually arrived in escrow;
+    pub fn collect(
+        &self,
+        subscriber_token_account: &AccountInfo<'info>,
+        subscription_account: &AccountInfo<'info>,
+        subscription_owner: &Pubkey,
+        program_id: &Pubkey,
+        amount: u64,
+    ) -> Result<u64> {
+        if amount == 0 {
+            return Ok(0);
+        }
+        let plan_key = self.plan_account.key();
+        let (_pda, subscription_bump) = Pubkey::find_program_address(
+            &[
+                b"subscription".as_ref(),
+                subscription_owner.as_ref(),
+                plan_key.as_ref(),
+            ],
+            program_id,
+        );
+        let transfer_accounts = TransferChecked {
+            from: subscriber_token_account.clone(),
+            mint: self.mint_account.clone(),
+            to: self.plan_token_account.clone(),
+            authority: subscription_account.clone(),
+        };
+        let before = self.balance()?;
+        transfer_checked(
+            CpiContext::new_with_signer(
+                self.token_program.clone(),
+                transfer_accounts,
+                &[&[
+                    b"subscription".as_ref(),
+                    subscription_owner.as_ref(),
+                    plan_key.as_ref(),
+                    &[subscription_bump],
+                ]],
+            ),
+            amount,
+            self.decimals,
+        )?;
+        self.received_since(before)
+    }
+
+    // bills the usage reported in a term that ends without a charge, e.g. when the subscription is
+    // closed early; like a finalized cancellation, whatever the subscriber can't pay now is written
+    // off; returns what actually arrived in escrow;
+    pub fn collect_final_usage(
+        &self,
+        plan: &Plan,
+        subscription_account: &Account<'info, Subscription>,
+        source: &InterfaceAccount<'info, TokenAccount>,
+        program_id: &Pubkey,
+    ) -> Result<u64> {
+        let allowance = charge_allowance(subscription_account, subscription_account.key(), source);
+        let usage = subscription_account
+            .overage(plan)?
+            .min(source.amount)
+            .min(allowance);
+        self.collect(
+            &source.to_account_info(),
+            &subscription_account.to_account_info(),
+            &subscription_account.owner,
+            program_id,
+            usage,
+        )
+    }
+
+    pub fn balance(&self) -> Result<u64> {
+        let data = self.plan_token_account.try_borrow_data()?;
+        Ok(TokenAccount::try_deserialize(&mut &data[..])?.amount)
+    }
+
+    pub fn received_since(&self, before: u64) -> Result<u64> {
+        self.balance()?
+            .checked_sub(before)
+            .ok_or(SubscriptionErrors::MathOverflow.into())
+    }
+
+    pub fn pay_out(
+        &self,
+        settlement: &Settlement,
+        owner_token_account: &AccountInfo<'info>,
+        deployer_token_account: &AccountInfo<'info>,
+        keeper_token_account: &AccountInfo<'info>,
+    ) -> Result<()> {
+        self.transfer_from_escrow(owner_token_account, settlement.owner_amount)?;
+        self.transfer_from_escrow(deployer_token_account, settlement.tax)?;
+        if settlement.reward > 0 {
+            self.transfer_from_escrow(keeper_token_account, settlement.reward)?;
+        }
+        Ok(())
+    }
+
+    pub fn transfer_from_escrow(&self, to: &AccountInfo<'info>, amount: u64) -> Result<()> {
+        let transfer_accounts = TransferChecked {
+            from: self.plan_token_account.clone(),
+            mint: self.mint_account.clone(),
+            to: to.clone(),
+            authority: self.plan_account.clone(),
+        };
+        transfer_checked(
+            CpiContext::new_with_signer(
+                self.token_program.clone(),
+                transfer_accounts,
+                &[self.plan_seeds],
+            ),
+            amount,
+            self.decimals,
+        )
+    }
 }
 
 #[derive(Accounts)]
@@ -109,41 +606,58 @@ pub struct ChargeSubscriptionParams<'info> {
     #[account(
         mut,
         seeds = [b"subscription".as_ref(), subscription_account.owner.key().as_ref(), plan_account.key().as_ref()],
+        constraint = subscription_account.state != SubscriptionState::Lapsed @ SubscriptionErrors::InvalidSubscriptionState,
+        constraint = subscription_account.state != SubscriptionState::Cancelled @ SubscriptionErrors::InvalidSubscriptionState,
         bump,
     )]
     pub subscription_account: Account<'info, Subscription>,
     #[account(
         mut,
-        seeds = [b"plan".as_ref(), plan_account.owner.key().as_ref(), plan_account.code.as_ref()],
+        seeds = [b"plan".as_ref(), plan_account.owner.key().as_ref(), plan_account.code_seed()],
         bump,
     )]
     pub plan_account: Account<'info, Plan>,
     #[account(
         mut,
-        constraint = plan_token_account.mint == plan_account.token_mint,
-        constraint = plan_token_account.owner == plan_account.key(),
+        constraint = plan_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = plan_token_account.owner == plan_account.key() @ SubscriptionErrors::InvalidTokenAccountOwner,
     )]
-    pub plan_token_account: Account<'info, TokenAccount>,
+    pub plan_token_account: InterfaceAccount<'info, TokenAccount>,
     #[account(
         mut,
-        constraint = subscriber_token_account.mint == plan_account.token_mint,
-        constraint = subscriber_token_account.owner == subscription_account.owner.key(),
+        constraint = subscriber_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = subscriber_token_account.key() == subscription_account.payer_token_account @ SubscriptionErrors::TokenAccountMismatch,
     )]
-    pub subscriber_token_account: Account<'info, TokenAccount>,
+    pub subscriber_token_account: InterfaceAccount<'info, TokenAccount>,
     #[account(
         mut,
-        constraint = owner_token_account.mint == plan_account.token_mint,
-        constraint = owner_token_account.owner == plan_account.owner.key(),
+        constraint = owner_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = owner_token_account.owner == plan_account.owner.key() @ SubscriptionErrors::InvalidTokenAccountOwner,
+    )]
+    pub owner_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = deployer_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = deployer_token_account.owner == protocol_config.fee_recipient @ SubscriptionErrors::InvalidTokenAccountOwner,
+    )]
+    pub deployer_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        seeds = [b"protocol_config".as_ref()],
+        bump,
     )]
-    pub owner_token_account: Account<'info, TokenAccount>,
+    pub protocol_config: Account<'info, ProtocolConfig>,
     #[account(
         mut,
-        constraint = deployer_token_account.mint == plan_account.token_mint,
-        constraint = deployer_token_account.owner == pubkey!("8mw8QFoqRffuYtwVDw4QD6eEfg1wEpYB24oL44toeZxy"),
+        constraint = keeper_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
     )]
-    pub deployer_token_account: Account<'info, TokenAccount>,
+    pub keeper_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(address = plan_account.token_mint @ SubscriptionErrors::MintMismatch)]
+    pub mint_account: InterfaceAccount<'info, Mint>,
+    /// CHECK: paid the subscription's rent and gets it back when a cancellation is finalized
+    #[account(mut, address = subscription_account.payer_authority @ SubscriptionErrors::PayerAuthorityMismatch)]
+    pub payer_authority: Option<UncheckedAccount<'info>>,
     #[account(mut)]
     pub payer: Signer<'info>,
     pub system_program: Program<'info, System>,
-    pub token_program: Program<'info, Token>,
+    pub token_program: Interface<'info, TokenInterface>,
 }
@@ -0,0 +1,193 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
+
+use crate::SubscriptionErrors;
+
+use super::{
+    charge_subscription::{
+        advance_subscription, charge_allowance, emit_charge_event, ChargeOutcome, PlanEscrow,
+        Settlement,
+    },
+    create_plan::Plan,
+    create_subscription::{Subscription, SubscriptionState},
+    initialize_protocol_config::ProtocolConfig,
+};
+
+pub fn handle_charge_subscriptions_batch<'info>(
+    ctx: Context<'_, '_, '_, 'info, ChargeSubscriptionsBatchParams<'info>>,
+) -> Result<()> {
+    // remaining accounts are (subscription, subscriber token account) pairs; subscriptions that
+    // aren't due are skipped and everything settled is paid out in one go at the end;
+    let pairs = ctx.remaining_accounts.chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return Err(SubscriptionErrors::InvalidBatchAccounts.into());
+    }
+    let plan_account = &mut ctx.accounts.plan_account;
+    let plan_token_account = &ctx.accounts.plan_token_account;
+    let owner_token_account = &ctx.accounts.owner_token_account;
+    let deployer_token_account = &ctx.accounts.deployer_token_account;
+    let keeper_token_account = &ctx.accounts.keeper_token_account;
+    let mint_account = &ctx.accounts.mint_account;
+    let protocol_config = &ctx.accounts.protocol_config;
+    let token_program = &ctx.accounts.token_program;
+    let plan_key = plan_account.key();
+    let current = Clock::get()?.unix_timestamp;
+
+    let plan_account_owner_key = plan_account.owner.key();
+    let plan_seed = plan_account.code_seed().to_vec();
+    let (_pda, plan_bump) = Pubkey::find_program_address(
+        &[
+            b"plan".as_ref(),
+            plan_account_owner_key.as_ref(),
+            plan_seed.as_ref(),
+        ],
+        ctx.program_id,
+    );
+    let escrow = PlanEscrow {
+        token_program: token_program.to_account_info(),
+        plan_account: plan_account.to_account_info(),
+        plan_token_account: plan_token_account.to_account_info(),
+        mint_account: mint_account.to_account_info(),
+        decimals: mint_account.decimals,
+        plan_seeds: &[
+            b"plan".as_ref(),
+            plan_account_owner_key.as_ref(),
+            plan_seed.as_ref(),
+            &[plan_bump],
+        ],
+    };
+
+    let mut settlement = Settlement::default();
+    for pair in pairs {
+        let subscription_info = &pair[0];
+        let subscriber_token_info = &pair[1];
+        let mut subscription_account = Account::<Subscription>::try_from(subscription_info)?;
+        let (pda, _bump) = Pubkey::find_program_address(
+            &[
+                b"subscription".as_ref(),
+                subscription_account.owner.as_ref(),
+                plan_key.as_ref(),
+            ],
+            ctx.program_id,
+        );
+        if pda != subscription_info.key() || !subscription_info.is_writable {
+            return Err(SubscriptionErrors::InvalidBatchAccounts.into());
+        }
+        if matches!(
+            subscription_account.state,
+            SubscriptionState::Lapsed | SubscriptionState::Cancelled
+        ) {
+            continue;
+        }
+        let subscriber_token_account =
+            InterfaceAccount::<TokenAccount>::try_from(subscriber_token_info)?;
+        if subscriber_token_account.mint != plan_account.token_mint
+            || subscriber_token_info.key() != subscription_account.payer_token_account
+        {
+            return Err(SubscriptionErrors::InvalidBatchAccounts.into());
+        }
+
+        let allowance = charge_allowance(
+            &subscription_account,
+            subscription_info.key(),
+            &subscriber_token_account,
+        );
+        let step = match advance_subscription(
+            plan_account,
+            &mut subscription_account,
+            current,
+            subscriber_token_account.amount,
+            allowance,
+        )? {
+            Some(step) => step,
+            None => continue,
+        };
+        let received = escrow.collect(
+            subscriber_token_info,
+            subscription_info,
+            &subscription_account.owner,
+            ctx.program_id,
+            step.collected,
+        )?;
+        let usage_received = escrow.collect(
+            subscriber_token_info,
+            subscription_info,
+            &subscription_account.owner,
+            ctx.program_id,
+            step.usage,
+        )?;
+        let term_settlement = Settlement::for_step(
+            &step,
+            received,
+            usage_received,
+            protocol_config.fee_bps,
+            &plan_account.keeper_reward,
+        )?;
+        if step.outcome == ChargeOutcome::Renewed {
+            subscription_account.term_price = received - term_settlement.advanced;
+        }
+        emit_charge_event(
+            plan_key,
+            &subscription_account,
+            subscription_info.key(),
+            &step,
+            &term_settlement,
+            current,
+        );
+        settlement.add(&term_settlement)?;
+        // persist now so a subscription listed twice is seen as already charged;
+        subscription_account.exit(ctx.program_id)?;
+    }
+
+    plan_account.record_payout(settlement.owner_amount, settlement.tax)?;
+    escrow.pay_out(
+        &settlement,
+        &owner_token_account.to_account_info(),
+        &deployer_token_account.to_account_info(),
+        &keeper_token_account.to_account_info(),
+    )
+}
+
+#[derive(Accounts)]
+pub struct ChargeSubscriptionsBatchParams<'info> {
+    #[account(
+        mut,
+        seeds = [b"plan".as_ref(), plan_account.owner.key().as_ref(), plan_account.code_seed()],
+        bump,
+    )]
+    pub plan_account: Account<'info, Plan>,
+    #[account(
+        mut,
+        constraint = plan_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = plan_token_account.owner == plan_account.key() @ SubscriptionErrors::InvalidTokenAccountOwner,
+    )]
+    pub plan_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = owner_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = owner_token_account.owner == plan_account.owner.key() @ SubscriptionErrors::InvalidTokenAccountOwner,
+    )]
+    pub owner_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = deployer_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = deployer_token_account.owner == protocol_config.fee_recipient @ SubscriptionErrors::InvalidTokenAccountOwner,
+    )]
+    pub deployer_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        seeds = [b"protocol_config".as_ref()],
+        bump,
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(
+        mut,
+        constraint = keeper_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+    )]
+    pub keeper_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(address = plan_account.token_mint @ SubscriptionErrors::MintMismatch)]
+    pub mint_account: InterfaceAccount<'info, Mint>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
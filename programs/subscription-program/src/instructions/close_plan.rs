@@ -0,0 +1,100 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{
+    close_account, transfer_checked, CloseAccount, Mint, TokenAccount, TokenInterface,
+    TransferChecked,
+};
+
+use crate::{events::PlanClosed, SubscriptionErrors};
+
+use super::create_plan::Plan;
+
+pub fn handle_close_plan(ctx: Context<ClosePlanParams>) -> Result<()> {
+    // drains whatever is left in escrow to the owner, then closes the escrow and the plan to reclaim rent;
+    let plan_account = &mut ctx.accounts.plan_account;
+    let plan_token_account = &ctx.accounts.plan_token_account;
+    let owner_token_account = &ctx.accounts.owner_token_account;
+    let mint_account = &ctx.accounts.mint_account;
+    let payer = &ctx.accounts.payer;
+    let token_program = &ctx.accounts.token_program;
+
+    let plan_account_owner_key = plan_account.owner.key();
+    let (_pda, bump) = Pubkey::find_program_address(
+        &[
+            b"plan".as_ref(),
+            plan_account_owner_key.as_ref(),
+            plan_account.code_seed(),
+        ],
+        ctx.program_id,
+    );
+    let signer_seeds: &[&[u8]] = &[
+        b"plan".as_ref(),
+        plan_account_owner_key.as_ref(),
+        plan_account.code_seed(),
+        &[bump],
+    ];
+    let drained = plan_token_account.amount;
+    if drained > 0 {
+        let drain_accounts = TransferChecked {
+            from: plan_token_account.to_account_info().clone(),
+            mint: mint_account.to_account_info().clone(),
+            to: owner_token_account.to_account_info().clone(),
+            authority: plan_account.to_account_info().clone(),
+        };
+        transfer_checked(
+            CpiContext::new_with_signer(
+                token_program.to_account_info().clone(),
+                drain_accounts,
+                &[signer_seeds],
+            ),
+            drained,
+            mint_account.decimals,
+        )?;
+    }
+    let close_accounts = CloseAccount {
+        account: plan_token_account.to_account_info().clone(),
+        destination: payer.to_account_info().clone(),
+        authority: plan_account.to_account_info().clone(),
+    };
+    close_account(CpiContext::new_with_signer(
+        token_program.to_account_info().clone(),
+        close_accounts,
+        &[signer_seeds],
+    ))?;
+    emit!(PlanClosed {
+        plan: plan_account.key(),
+        drained,
+        timestamp: Clock::get()?.unix_timestamp,
+    });
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct ClosePlanParams<'info> {
+    #[account(
+        mut,
+        seeds = [b"plan".as_ref(), plan_account.owner.key().as_ref(), plan_account.code_seed()],
+        constraint = plan_account.owner == payer.key() @ SubscriptionErrors::Unauthorized,
+        constraint = plan_account.live_subscriptions() == 0 @ SubscriptionErrors::PlanHasLiveSubscriptions,
+        bump,
+        close = payer,
+    )]
+    pub plan_account: Account<'info, Plan>,
+    #[account(
+        mut,
+        constraint = plan_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = plan_token_account.owner == plan_account.key() @ SubscriptionErrors::InvalidTokenAccountOwner,
+    )]
+    pub plan_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = owner_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = owner_token_account.owner == plan_account.owner.key() @ SubscriptionErrors::InvalidTokenAccountOwner,
+    )]
+    pub owner_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(address = plan_account.token_mint @ SubscriptionErrors::MintMismatch)]
+    pub mint_account: InterfaceAccount<'info, Mint>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
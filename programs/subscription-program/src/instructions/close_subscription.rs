@@ -1,8 +1,16 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{revoke, transfer, Revoke, Token, TokenAccount, Transfer};
-use solana_program::pubkey;
+use anchor_spl::token_interface::{revoke, Mint, Revoke, TokenAccount, TokenInterface};
 
-use super::{create_plan::Plan, create_subscription::Subscription};
+use crate::{
+    events::SubscriptionClosed,
+    math::{prorate, split_fee, RoundingPolicy},
+    SubscriptionErrors,
+};
+
+use super::{
+    charge_subscription::PlanEscrow, create_plan::Plan, create_subscription::Subscription,
+    gift_subscription::GiftVault, initialize_protocol_config::ProtocolConfig,
+};
 
 pub fn handle_close_subscription(ctx: Context<CloseSubscriptionParams>) -> Result<()> {
     // close immediately, closes the subscription account and refunds the user for the remaining time;
@@ -10,142 +18,211 @@ pub fn handle_close_subscription(ctx: Context<CloseSubscriptionParams>) -> Resul
     let plan_account = &mut ctx.accounts.plan_account;
     let plan_token_account = &mut ctx.accounts.plan_token_account;
     let payer = &ctx.accounts.payer;
-    let payer_token_account = &mut ctx.accounts.payer_token_account;
+    let payer_token_account = &ctx.accounts.payer_token_account;
     let plan_owner_token_account = &ctx.accounts.plan_owner_token_account;
     let deployer_token_account = &ctx.accounts.deployer_token_account;
+    let protocol_config = &ctx.accounts.protocol_config;
+    let mint_account = &ctx.accounts.mint_account;
     let token_program = &ctx.accounts.token_program;
     let current = Clock::get()?.unix_timestamp;
-    plan_account.active_subscriptions.checked_sub(1).or(Some(0));
-    // the subscription end date is in the future so the user needs a refund for the remaining time;
-    if current < subscription_account.next_term_date {
-        let term_seconds = plan_account.term_in_seconds;
-        msg!("term seconds {}", term_seconds);
-        let time_diff = subscription_account.next_term_date - current;
-        msg!("time diff {}", time_diff);
-        let percentage = time_diff as f64 / term_seconds as f64;
-        msg!("percentage {}", percentage);
-        let refund = (plan_account.price as f64 * percentage) as u64;
-        msg!("refund {}", refund);
-        let payer_payout = Transfer {
-            from: plan_token_account.to_account_info().clone(),
-            to: payer_token_account.to_account_info().clone(),
-            authority: plan_account.to_account_info().clone(),
-        };
-        let plan_account_owner_key = plan_account.owner.key();
-        let seeds = &[
+    let term_clock = subscription_account.term_clock(current);
+    plan_account.track_state(Some(&subscription_account.state), None)?;
+    let plan_account_owner_key = plan_account.owner.key();
+    let seeds = &[
+        b"plan".as_ref(),
+        plan_account_owner_key.as_ref(),
+        plan_account.code_seed(),
+    ];
+    let (_pda, bump) = Pubkey::find_program_address(seeds, ctx.program_id);
+    let escrow = PlanEscrow {
+        token_program: token_program.to_account_info(),
+        plan_account: plan_account.to_account_info(),
+        plan_token_account: plan_token_account.to_account_info(),
+        mint_account: mint_account.to_account_info(),
+        decimals: mint_account.decimals,
+        plan_seeds: &[
             b"plan".as_ref(),
             plan_account_owner_key.as_ref(),
-            plan_account.code.as_ref(),
-        ];
-        let (_pda, bump) = Pubkey::find_program_address(seeds, ctx.program_id);
-        transfer(
-            CpiContext::new_with_signer(
-                token_program.to_account_info().clone(),
-                payer_payout,
-                &[&[
-                    b"plan".as_ref(),
-                    plan_account_owner_key.as_ref(),
-                    plan_account.code.as_ref(),
-                    &[bump],
-                ]],
-            ),
-            refund,
-        )?;
+            plan_account.code_seed(),
+            &[bump],
+        ],
+    };
+    // usage reported this term is billed now since the charge that would have billed it won't
+    // happen, a gift pays for it out of its vault;
+    let usage_source = if subscription_account.gifted {
+        ctx.accounts
+            .gift_vault
+            .as_ref()
+            .ok_or(SubscriptionErrors::MissingGiftVault)?
+    } else {
+        payer_token_account
+    };
+    let usage = escrow.collect_final_usage(
+        plan_account,
+        subscription_account,
+        usage_source,
+        ctx.program_id,
+    )?;
+    // the unused part of the term is refunded and the rest settled; a term that's already over but
+    // hasn't been charged yet has nothing to refund and is settled in full;
+    let mut refund =
+        unused_term_credit(subscription_account, term_clock, protocol_config.rounding)?;
+    if refund > 0 {
+        escrow.transfer_from_escrow(&payer_token_account.to_account_info(), refund)?;
+    }
+    let settled = subscription_account
+        .term_price
+        .checked_sub(refund)
+        .and_then(|used| used.checked_add(usage))
+        .ok_or(SubscriptionErrors::MathOverflow)?;
+    let (owner_amount, tax) = split_fee(settled, protocol_config.fee_bps)?;
+    escrow.transfer_from_escrow(&plan_owner_token_account.to_account_info(), owner_amount)?;
+    escrow.transfer_from_escrow(&deployer_token_account.to_account_info(), tax)?;
 
-        let owner_payout = Transfer {
-            from: plan_token_account.to_account_info().clone(),
-            to: plan_owner_token_account.to_account_info().clone(),
-            authority: plan_account.to_account_info().clone(),
+    if subscription_account.gifted {
+        // terms the gifter paid for that haven't started yet go back to them with the vault's rent;
+        let gift_vault = ctx
+            .accounts
+            .gift_vault
+            .as_mut()
+            .ok_or(SubscriptionErrors::MissingGiftVault)?;
+        gift_vault.reload()?;
+        let subscription_owner_key = subscription_account.owner.key();
+        let plan_key = plan_account.key();
+        let (_pda, subscription_bump) = Pubkey::find_program_address(
+            &[
+                b"subscription".as_ref(),
+                subscription_owner_key.as_ref(),
+                plan_key.as_ref(),
+            ],
+            ctx.program_id,
+        );
+        let vault = GiftVault {
+            token_program: token_program.to_account_info(),
+            subscription_account: subscription_account.to_account_info(),
+            vault: gift_vault.to_account_info(),
+            mint_account: mint_account.to_account_info(),
+            decimals: mint_account.decimals,
+            subscription_seeds: &[
+                b"subscription".as_ref(),
+                subscription_owner_key.as_ref(),
+                plan_key.as_ref(),
+                &[subscription_bump],
+            ],
         };
-        let total = plan_account.price - refund;
-        let tax = ((total as f64) * 0.03) as u64;
-        transfer(
-            CpiContext::new_with_signer(
-                token_program.to_account_info().clone(),
-                owner_payout,
-                &[&[
-                    b"plan".as_ref(),
-                    plan_account_owner_key.as_ref(),
-                    plan_account.code.as_ref(),
-                    &[bump],
-                ]],
-            ),
-            total - tax,
+        vault.refund_and_close(
+            gift_vault.amount,
+            &payer_token_account.to_account_info(),
+            &ctx.accounts.payer_authority.to_account_info(),
         )?;
-
-        let tax_accounts = Transfer {
-            from: plan_token_account.to_account_info().clone(),
-            to: deployer_token_account.to_account_info().clone(),
-            authority: plan_account.to_account_info().clone(),
+        refund = refund
+            .checked_add(gift_vault.amount)
+            .ok_or(SubscriptionErrors::MathOverflow)?;
+    } else if payer.key() == subscription_account.payer_authority {
+        // only the token account owner can revoke, a sponsored subscription closed by its
+        // beneficiary leaves the delegation for the payer authority to revoke;
+        let revoke_accounts = Revoke {
+            authority: payer.to_account_info().clone(),
+            source: payer_token_account.to_account_info().clone(),
         };
-        transfer(
-            CpiContext::new_with_signer(
-                token_program.to_account_info().clone(),
-                tax_accounts,
-                &[&[
-                    b"plan".as_ref(),
-                    plan_account_owner_key.as_ref(),
-                    plan_account.code.as_ref(),
-                    &[bump],
-                ]],
-            ),
-            tax,
-        )?;
+        revoke(CpiContext::new(
+            token_program.to_account_info().clone(),
+            revoke_accounts,
+        ))?;
     }
-
-    let revoke_accounts = Revoke {
-        authority: payer.to_account_info().clone(),
-        source: payer_token_account.to_account_info().clone(),
-    };
-    revoke(CpiContext::new(
-        token_program.to_account_info().clone(),
-        revoke_accounts,
-    ))?;
+    plan_account.record_payout(owner_amount, tax)?;
+    plan_account.record_refund(refund)?;
+    emit!(SubscriptionClosed {
+        plan: plan_account.key(),
+        subscription: subscription_account.key(),
+        subscriber: subscription_account.owner,
+        term_index: subscription_account.term_index,
+        refund,
+        usage_amount: usage,
+        owner_amount,
+        fee: tax,
+        timestamp: current,
+    });
     Ok(())
 }
 
+// what escrow still holds for the part of the current term that hasn't been used yet;
+pub(crate) fn unused_term_credit(
+    subscription: &Subscription,
+    current: i64,
+    rounding: RoundingPolicy,
+) -> Result<u64> {
+    if current >= subscription.next_term_date {
+        return Ok(0);
+    }
+    let time_diff = (subscription.next_term_date - current) as u64;
+    prorate(
+        subscription.term_price,
+        time_diff,
+        subscription.term_in_seconds,
+        rounding,
+    )
+}
+
 #[derive(Accounts)]
 pub struct CloseSubscriptionParams<'info> {
     #[account(
         mut,
         seeds = [b"subscription".as_ref(), subscription_account.owner.key().as_ref(), plan_account.key().as_ref()],
-        constraint = subscription_account.owner == payer.key(),
+        constraint = plan_account.cancel_authority.allows(&subscription_account, payer.key()) @ SubscriptionErrors::Unauthorized,
         bump,
-        close = payer,
+        close = payer_authority,
     )]
     pub subscription_account: Account<'info, Subscription>,
     #[account(
         mut,
-        seeds = [b"plan".as_ref(), plan_account.owner.key().as_ref(), plan_account.code.as_ref()],
+        seeds = [b"plan".as_ref(), plan_account.owner.key().as_ref(), plan_account.code_seed()],
         bump,
     )]
     pub plan_account: Account<'info, Plan>,
     #[account(
         mut,
-        constraint = plan_token_account.mint == plan_account.token_mint,
-        constraint = plan_token_account.owner == plan_account.key(),
+        constraint = plan_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = plan_token_account.owner == plan_account.key() @ SubscriptionErrors::InvalidTokenAccountOwner,
     )]
-    pub plan_token_account: Account<'info, TokenAccount>,
+    pub plan_token_account: InterfaceAccount<'info, TokenAccount>,
     #[account(
         mut,
-        constraint = payer_token_account.mint == plan_account.token_mint,
-        constraint = payer_token_account.owner == subscription_account.owner.key(),
+        constraint = payer_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = payer_token_account.owner == subscription_account.payer_authority @ SubscriptionErrors::InvalidTokenAccountOwner,
+        // a gift's own token account is its vault, the rest of it goes back to the gifter's;
+        constraint = subscription_account.gifted || payer_token_account.key() == subscription_account.payer_token_account @ SubscriptionErrors::TokenAccountMismatch,
     )]
-    pub payer_token_account: Account<'info, TokenAccount>,
+    pub payer_token_account: InterfaceAccount<'info, TokenAccount>,
     #[account(
         mut,
-        constraint = plan_owner_token_account.mint == plan_account.token_mint,
-        constraint = plan_owner_token_account.owner == plan_account.owner.key(),
+        constraint = plan_owner_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = plan_owner_token_account.owner == plan_account.owner.key() @ SubscriptionErrors::InvalidTokenAccountOwner,
+    )]
+    pub plan_owner_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = deployer_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = deployer_token_account.owner == protocol_config.fee_recipient @ SubscriptionErrors::InvalidTokenAccountOwner,
+    )]
+    pub deployer_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        seeds = [b"protocol_config".as_ref()],
+        bump,
     )]
-    pub plan_owner_token_account: Account<'info, TokenAccount>,
+    pub protocol_config: Account<'info, ProtocolConfig>,
     #[account(
         mut,
-        constraint = deployer_token_account.mint == plan_account.token_mint,
-        constraint = deployer_token_account.owner == pubkey!("8mw8QFoqRffuYtwVDw4QD6eEfg1wEpYB24oL44toeZxy"),
+        address = subscription_account.payer_token_account @ SubscriptionErrors::TokenAccountMismatch,
     )]
-    pub deployer_token_account: Account<'info, TokenAccount>,
+    pub gift_vault: Option<InterfaceAccount<'info, TokenAccount>>,
+    #[account(address = plan_account.token_mint @ SubscriptionErrors::MintMismatch)]
+    pub mint_account: InterfaceAccount<'info, Mint>,
+    /// CHECK: paid the subscription's rent and gets it back
+    #[account(mut, address = subscription_account.payer_authority @ SubscriptionErrors::PayerAuthorityMismatch)]
+    pub payer_authority: UncheckedAccount<'info>,
     #[account(mut)]
     pub payer: Signer<'info>,
     pub system_program: Program<'info, System>,
-    pub token_program: Program<'info, Token>,
+    pub token_program: Interface<'info, TokenInterface>,
 }
@@ -0,0 +1,155 @@
+use anchor_lang::prelude::*;
+
+use crate::{
+    events::CouponCreated,
+    math::{fee_amount, BPS_DENOMINATOR},
+    SubscriptionErrors,
+};
+
+use super::create_plan::Plan;
+
+pub const MAX_COUPON_CODE_LEN: usize = 32;
+
+pub fn handle_create_coupon(
+    ctx: Context<CreateCouponParams>,
+    data: CreateCouponData,
+) -> Result<()> {
+    let coupon_account = &mut ctx.accounts.coupon_account;
+    let plan_account = &ctx.accounts.plan_account;
+    // the code is a seed of the coupon's address, so it has to fit in one and be typeable;
+    if data.code.is_empty()
+        || data.code.len() > MAX_COUPON_CODE_LEN
+        || !data.code.bytes().all(|c| c.is_ascii_graphic())
+    {
+        return Err(SubscriptionErrors::InvalidCouponCode.into());
+    }
+    match data.discount {
+        Discount::None => return Err(SubscriptionErrors::InvalidCoupon.into()),
+        Discount::Bps(bps) if bps as u128 > BPS_DENOMINATOR => {
+            return Err(SubscriptionErrors::InvalidCoupon.into())
+        }
+        _ => {}
+    }
+    if data.duration_terms == 0 {
+        return Err(SubscriptionErrors::InvalidCoupon.into());
+    }
+    coupon_account.plan_account = plan_account.key();
+    coupon_account.code = data.code;
+    coupon_account.discount = data.discount;
+    coupon_account.duration_terms = data.duration_terms;
+    coupon_account.max_redemptions = data.max_redemptions;
+    coupon_account.redemptions = 0;
+    coupon_account.expires_at = data.expires_at;
+    emit!(CouponCreated {
+        plan: plan_account.key(),
+        coupon: coupon_account.key(),
+        code: coupon_account.code.clone(),
+        discount: coupon_account.discount.clone(),
+        duration_terms: coupon_account.duration_terms,
+        max_redemptions: coupon_account.max_redemptions,
+        expires_at: coupon_account.expires_at,
+        timestamp: Clock::get()?.unix_timestamp,
+    });
+    Ok(())
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Debug, PartialEq)]
+pub enum Discount {
+    #[default]
+    None,
+    // taken off the price of each discounted term;
+    Fixed(u64),
+    Bps(u16),
+}
+
+impl Discount {
+    pub fn apply(&self, price: u64) -> Result<u64> {
+        let off = match self {
+            Discount::None => 0,
+            Discount::Fixed(amount) => *amount,
+            Discount::Bps(bps) => fee_amount(price, *bps)?,
+        };
+        Ok(price.saturating_sub(off))
+    }
+}
+
+#[account]
+pub struct Coupon {
+    pub plan_account: Pubkey, // 32
+    pub code: String,         // 4 + 32 = 36
+    pub discount: Discount,   // 1 + 8 = 9
+    // number of charged terms the discount applies to once redeemed;
+    pub duration_terms: u32, // 4
+    // zero means unlimited;
+    pub max_redemptions: u32, // 4
+    pub redemptions: u32,     // 4
+    // zero means it never expires;
+    pub expires_at: i64, // 8
+}
+
+impl Coupon {
+    pub fn redeem(&mut self, current: i64) -> Result<()> {
+        if self.expires_at != 0 && current > self.expires_at {
+            return Err(SubscriptionErrors::CouponExpired.into());
+        }
+        if self.max_redemptions != 0 && self.redemptions >= self.max_redemptions {
+            return Err(SubscriptionErrors::CouponExhausted.into());
+        }
+        self.redemptions += 1;
+        Ok(())
+    }
+}
+
+// outlives the subscription so a subscriber can't redeem the same coupon again, by applying it
+// once the discount is used up or by closing and subscribing again;
+#[account]
+pub struct CouponRedemption {
+    pub coupon_account: Pubkey, // 32
+    pub owner: Pubkey,          // 32
+    pub redeemed_at: i64,       // 8
+}
+
+impl CouponRedemption {
+    pub const SPACE: usize = 8 + 32 + 32 + 8;
+
+    pub fn record(&mut self, coupon: Pubkey, owner: Pubkey, current: i64) -> Result<()> {
+        if self.redeemed_at != 0 {
+            return Err(SubscriptionErrors::CouponAlreadyRedeemed.into());
+        }
+        self.coupon_account = coupon;
+        self.owner = owner;
+        self.redeemed_at = current;
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(code: String)]
+pub struct CreateCouponParams<'info> {
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + 32 + 36 + 9 + 4 + 4 + 4 + 8,
+        seeds = [b"coupon".as_ref(), plan_account.key().as_ref(), code.as_ref()],
+        bump,
+    )]
+    pub coupon_account: Account<'info, Coupon>,
+    #[account(
+        seeds = [b"plan".as_ref(), plan_account.owner.key().as_ref(), plan_account.code_seed()],
+        constraint = plan_account.owner == payer.key() @ SubscriptionErrors::Unauthorized,
+        bump,
+    )]
+    pub plan_account: Account<'info, Plan>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Debug)]
+pub struct CreateCouponData {
+    pub code: String,
+    pub discount: Discount,
+    pub duration_terms: u32,
+    pub max_redemptions: u32,
+    pub expires_at: i64,
+}
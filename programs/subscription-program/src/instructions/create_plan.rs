@@ -1,16 +1,62 @@
-use anchor_lang::prelude::*;
-use anchor_spl::{token::{Token, TokenAccount, Mint}, associated_token::AssociatedToken};
+use anchor_lang::{prelude::*, solana_program::hash::hash};
+use anchor_spl::{token_interface::{TokenInterface, TokenAccount, Mint}, associated_token::AssociatedToken};
 
+use crate::{events::PlanCreated, math::BPS_DENOMINATOR, SubscriptionErrors};
+
+use super::create_subscription::{Subscription, SubscriptionState};
+
+pub const MAX_CODE_LEN: usize = 64;
+pub const MAX_NAME_LEN: usize = 64;
+pub const MAX_METADATA_URI_LEN: usize = 200;
+// longest term, trial, retry interval, grace period or pause a plan can have;
+pub const MAX_DURATION_SECONDS: u64 = 100 * 365 * 24 * 60 * 60;
 
 pub fn handle_create_plan(ctx: Context<CreatePlanParams>, data: CreatePlanData) -> Result<()> {
     let plan_account = &mut ctx.accounts.plan_account;
     let plan_token_account = &mut ctx.accounts.plan_token_account;
+    Plan::validate_code(&data.code)?;
+    Plan::validate_terms(data.price, data.unit_price, data.term_in_seconds)?;
+    Plan::validate_metadata(&data.name, &data.metadata_uri)?;
+    // the address is derived from a hash of the code so it isn't limited by the seed length;
+    plan_account.code_hash = hash(data.code.as_bytes()).to_bytes();
     plan_account.code = data.code;
+    plan_account.name = data.name;
+    plan_account.metadata_uri = data.metadata_uri;
     plan_account.owner = *ctx.accounts.payer.key;
     plan_account.price = data.price;
     plan_account.token_mint = plan_token_account.mint;
     plan_account.term_in_seconds = data.term_in_seconds;
-    plan_account.active_subscriptions = 0;
+    plan_account.version = 0;
+    plan_account.state = PlanState::Active;
+    plan_account.trial_seconds = data.trial_seconds;
+    plan_account.retry_limit = data.retry_limit;
+    plan_account.retry_interval_seconds = data.retry_interval_seconds;
+    plan_account.grace_seconds = data.grace_seconds;
+    plan_account.keeper_reward = data.keeper_reward;
+    plan_account.usage_authority = data.usage_authority;
+    plan_account.unit_price = data.unit_price;
+    plan_account.included_units = data.included_units;
+    plan_account.cancel_authority = data.cancel_authority;
+    if data.prepay_discount_bps as u128 > BPS_DENOMINATOR {
+        return Err(SubscriptionErrors::InvalidPrepayDiscount.into());
+    }
+    plan_account.prepay_discount_bps = data.prepay_discount_bps;
+    plan_account.max_pause_seconds = data.max_pause_seconds;
+    plan_account.max_pauses_per_year = data.max_pauses_per_year;
+    plan_account.layout_version = Plan::LAYOUT_VERSION;
+    plan_account.validate_durations()?;
+    emit!(PlanCreated {
+        plan: plan_account.key(),
+        owner: plan_account.owner,
+        code: plan_account.code.clone(),
+        name: plan_account.name.clone(),
+        metadata_uri: plan_account.metadata_uri.clone(),
+        token_mint: plan_account.token_mint,
+        price: plan_account.price,
+        term_in_seconds: plan_account.term_in_seconds,
+        trial_seconds: plan_account.trial_seconds,
+        timestamp: Clock::get()?.unix_timestamp,
+    });
     Ok(())
 }
 
@@ -22,8 +68,8 @@ pub struct CreatePlanParams<'info> {
     #[account(
         init, 
         payer = payer, 
-        space = 8 + 36 + 32 + 8 + 32 + 8 + 4, 
-        seeds = [b"plan".as_ref(), payer.key().as_ref(), code.as_ref()],
+        space = Plan::SPACE,
+        seeds = [b"plan".as_ref(), payer.key().as_ref(), hash(code.as_bytes()).as_ref()],
         bump
     )]
     pub plan_account: Account<'info, Plan>,
@@ -32,31 +78,269 @@ pub struct CreatePlanParams<'info> {
         payer = payer,
         associated_token::mint = mint_account,
         associated_token::authority = plan_account,
+        associated_token::token_program = token_program,
     )]
-    pub plan_token_account: Account<'info, TokenAccount>,
+    pub plan_token_account: InterfaceAccount<'info, TokenAccount>,
     #[account()]
-    pub mint_account: Account<'info, Mint>,
+    pub mint_account: InterfaceAccount<'info, Mint>,
     pub associated_token_program: Program<'info, AssociatedToken>,
     #[account(mut)]
     pub payer: Signer<'info>,
     pub system_program: Program<'info, System>,
-    pub token_program: Program<'info, Token>,
+    pub token_program: Interface<'info, TokenInterface>,
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Debug)]
 pub struct CreatePlanData {
     pub code: String,
+    pub name: String,
+    pub metadata_uri: String,
     pub price: u64,
     pub term_in_seconds: u64,
+    pub trial_seconds: u64,
+    pub retry_limit: u8,
+    pub retry_interval_seconds: u64,
+    pub grace_seconds: u64,
+    pub keeper_reward: KeeperReward,
+    pub usage_authority: Pubkey,
+    pub unit_price: u64,
+    pub included_units: u64,
+    pub cancel_authority: CancelAuthority,
+    pub prepay_discount_bps: u16,
+    pub max_pause_seconds: u64,
+    pub max_pauses_per_year: u8,
+}
+
+
+// a closed plan has no state, close_plan deletes the account once nothing on it is live;
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Debug, PartialEq)]
+pub enum PlanState {
+    // open to new subscribers, existing subscriptions renew;
+    #[default]
+    Active,
+    // existing subscriptions keep renewing but no one new can subscribe;
+    ClosedToNewSubscribers,
+    // existing subscriptions finish out their current term and are not renewed;
+    Sunset,
+}
+
+// paid to whoever calls charge_subscription, out of the owner's share of the term being settled,
+// or of the one being collected when a renewal settles nothing;
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Debug, PartialEq)]
+pub enum KeeperReward {
+    #[default]
+    None,
+    Fixed(u64),
+    Bps(u16),
+}
+
+// who can cancel or close a subscription when the beneficiary isn't the one paying for it;
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Debug, PartialEq)]
+pub enum CancelAuthority {
+    #[default]
+    Either,
+    PayerAuthority,
+    Beneficiary,
 }
 
+impl CancelAuthority {
+    pub fn allows(&self, subscription: &Subscription, signer: Pubkey) -> bool {
+        let is_beneficiary = subscription.owner == signer;
+        let is_payer_authority = subscription.payer_authority == signer;
+        match self {
+            CancelAuthority::Either => is_beneficiary || is_payer_authority,
+            CancelAuthority::PayerAuthority => is_payer_authority,
+            CancelAuthority::Beneficiary => is_beneficiary,
+        }
+    }
+}
 
 #[account]
 pub struct Plan {
-    pub code: String,                   // 4 + 32 = 36
+    pub code: String,                   // 4 + 64 = 68
     pub owner: Pubkey,                  // 32
     pub price: u64,                     // 8
     pub token_mint: Pubkey,             // 32
     pub term_in_seconds: u64,           // 8
-    pub active_subscriptions: u32,      // 4
+    // subscriptions currently in the Active state;
+    pub active_subscriptions: u64,      // 8
+    pub version: u32,                   // 4
+    pub state: PlanState,               // 1
+    pub trial_seconds: u64,             // 8
+    // dunning: how many times and how often a past due charge is retried before it lapses;
+    pub retry_limit: u8,                // 1
+    pub retry_interval_seconds: u64,    // 8
+    pub grace_seconds: u64,             // 8
+    pub keeper_reward: KeeperReward,    // 1 + 8 = 9
+    // metering: who can report usage, and what each unit over the included ones costs;
+    pub usage_authority: Pubkey,        // 32
+    pub unit_price: u64,                // 8
+    pub included_units: u64,            // 8
+    pub cancel_authority: CancelAuthority, // 1
+    // taken off subscriptions that pay for more than one term up front;
+    pub prepay_discount_bps: u16,       // 2
+    // pausing: how long a pause can last, zero for no limit, and how many pauses a subscription
+    // gets in a year, zero to turn pausing off;
+    pub max_pause_seconds: u64,         // 8
+    pub max_pauses_per_year: u8,        // 1
+    // kpis: how many subscriptions are in the other live states, how many were ever started, and
+    // what has been paid out to the owner, taken in protocol fees and refunded to subscribers;
+    pub pending_cancellation_subscriptions: u64, // 8
+    pub past_due_subscriptions: u64,    // 8
+    pub paused_subscriptions: u64,      // 8
+    pub lifetime_subscriptions: u64,    // 8
+    pub lifetime_revenue: u64,          // 8
+    pub total_fees: u64,                // 8
+    pub total_refunds: u64,             // 8
+    // layout the account was written with, accounts from before it existed read as zero so
+    // migrate_plan knows which fields to fill in; fixed-size fields added after it are taken out
+    // of reserved and leave SPACE as it is, only strings have to grow it;
+    pub layout_version: u8,             // 1
+    // what the address was derived from instead of the code, zero for plans from before codes were
+    // hashed which keep the address derived from the code itself;
+    pub code_hash: [u8; 32],            // 32
+    // shown to subscribers, the code is what integrations refer to the plan by;
+    pub name: String,                   // 4 + 64 = 68
+    // off-chain description of the plan;
+    pub metadata_uri: String,           // 4 + 200 = 204
+    // subscriptions from before layouts were versioned that haven't been migrated yet, and when
+    // the last term any of them could have been paid for ends;
+    pub unmigrated_subscriptions: u64,  // 8
+    pub unmigrated_terms_end: i64,      // 8
+    pub reserved: [u8; 16],             // 64 - 32 - 16 = 16
+}
+
+impl Plan {
+    pub const LAYOUT_VERSION: u8 = 2;
+    pub const SPACE: usize =
+        8 + 68 + 32 + 8 + 32 + 8 + 8 + 4 + 1 + 8 + 1 + 8 + 8 + 9 + 32 + 8 + 8 + 1 + 2 + 8 + 1 + 56
+            + 1 + 32 + 68 + 204 + 8 + 8 + 16;
+
+    // the last plan seed, after the owner;
+    pub fn code_seed(&self) -> &[u8] {
+        if self.code_hash == [0; 32] {
+            return self.code.as_bytes();
+        }
+        &self.code_hash
+    }
+
+    // codes are what integrations refer to a plan by, so they're kept to printable ascii;
+    pub fn validate_code(code: &str) -> Result<()> {
+        if code.is_empty()
+            || code.len() > MAX_CODE_LEN
+            || !code.bytes().all(|c| c.is_ascii_graphic())
+        {
+            return Err(SubscriptionErrors::InvalidPlanCode.into());
+        }
+        Ok(())
+    }
+
+    // a zero length term can't be prorated and a free term would never be worth charging, unless
+    // it's a metered plan that only charges for usage;
+    pub fn validate_terms(price: u64, unit_price: u64, term_in_seconds: u64) -> Result<()> {
+        if term_in_seconds == 0 {
+            return Err(SubscriptionErrors::InvalidTerm.into());
+        }
+        if price == 0 && unit_price == 0 {
+            return Err(SubscriptionErrors::InvalidPrice.into());
+        }
+        Ok(())
+    }
+
+    // keeps every date worked out from the plan well inside an i64 timestamp;
+    pub fn validate_durations(&self) -> Result<()> {
+        let durations = [
+            self.term_in_seconds,
+            self.trial_seconds,
+            self.retry_interval_seconds,
+            self.grace_seconds,
+            self.max_pause_seconds,
+        ];
+        if durations.iter().any(|seconds| *seconds > MAX_DURATION_SECONDS) {
+            return Err(SubscriptionErrors::InvalidDuration.into());
+        }
+        Ok(())
+    }
+
+    pub fn validate_metadata(name: &str, metadata_uri: &str) -> Result<()> {
+        if name.len() > MAX_NAME_LEN || metadata_uri.len() > MAX_METADATA_URI_LEN {
+            return Err(SubscriptionErrors::InvalidPlanMetadata.into());
+        }
+        Ok(())
+    }
+
+    // keeps the per-state counters in step with a subscription moving between states, None being a
+    // subscription that is being created or closed;
+    pub fn track_state(
+        &mut self,
+        from: Option<&SubscriptionState>,
+        to: Option<&SubscriptionState>,
+    ) -> Result<()> {
+        if let Some(counter) = from.and_then(|state| self.state_counter(state)) {
+            *counter = counter.saturating_sub(1);
+        }
+        if let Some(counter) = to.and_then(|state| self.state_counter(state)) {
+            *counter = counter
+                .checked_add(1)
+                .ok_or(SubscriptionErrors::MathOverflow)?;
+        }
+        if from.is_none() && to.is_some() {
+            self.lifetime_subscriptions = self
+                .lifetime_subscriptions
+                .checked_add(1)
+                .ok_or(SubscriptionErrors::MathOverflow)?;
+        }
+        Ok(())
+    }
+
+    fn state_counter(&mut self, state: &SubscriptionState) -> Option<&mut u64> {
+        match state {
+            SubscriptionState::Active => Some(&mut self.active_subscriptions),
+            SubscriptionState::PendingCancellation => {
+                Some(&mut self.pending_cancellation_subscriptions)
+            }
+            // either way the renewal hasn't been paid;
+            SubscriptionState::PastDue | SubscriptionState::AllowanceExhausted => {
+                Some(&mut self.past_due_subscriptions)
+            }
+            SubscriptionState::Paused => Some(&mut self.paused_subscriptions),
+            SubscriptionState::Lapsed | SubscriptionState::Cancelled => None,
+        }
+    }
+
+    // subscriptions that can still be charged, refunded or closed against the escrow;
+    pub fn live_subscriptions(&self) -> u64 {
+        self.unmigrated_subscriptions
+            .saturating_add(self.active_subscriptions)
+            .saturating_add(self.pending_cancellation_subscriptions)
+            .saturating_add(self.past_due_subscriptions)
+            .saturating_add(self.paused_subscriptions)
+    }
+
+    pub fn record_payout(&mut self, owner_amount: u64, fee: u64) -> Result<()> {
+        self.lifetime_revenue = self
+            .lifetime_revenue
+            .checked_add(owner_amount)
+            .ok_or(SubscriptionErrors::MathOverflow)?;
+        self.total_fees = self
+            .total_fees
+            .checked_add(fee)
+            .ok_or(SubscriptionErrors::MathOverflow)?;
+        Ok(())
+    }
+
+    pub fn record_refund(&mut self, amount: u64) -> Result<()> {
+        self.total_refunds = self
+            .total_refunds
+            .checked_add(amount)
+            .ok_or(SubscriptionErrors::MathOverflow)?;
+        Ok(())
+    }
+
+    // the price of one term for `quantity` seats;
+    pub fn price_for(&self, quantity: u32) -> Result<u64> {
+        self.price
+            .checked_mul(quantity as u64)
+            .ok_or(SubscriptionErrors::MathOverflow.into())
+    }
 }
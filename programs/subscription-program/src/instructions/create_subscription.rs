@@ -1,7 +1,16 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{Token, TokenAccount, Approve, approve, Transfer, transfer};
+use anchor_spl::token_interface::{TokenInterface, TokenAccount, Mint, Approve, approve, TransferChecked, transfer_checked};
 
-use super::create_plan::Plan;
+use crate::{
+    events::{CouponApplied, SubscriptionCreated},
+    math::{add_seconds, fee_amount},
+    SubscriptionErrors,
+};
+
+use super::{
+    create_coupon::{Coupon, CouponRedemption, Discount},
+    create_plan::{Plan, PlanState},
+};
 
 
 pub fn handle_create_subscription(
@@ -12,15 +21,91 @@ pub fn handle_create_subscription(
     let subscription_account = &mut ctx.accounts.subscription_account;
     let payer_token_account = &mut ctx.accounts.payer_token_account;
     let plan_token_account = &mut ctx.accounts.plan_token_account;
+    let trial_account = &mut ctx.accounts.trial_account;
+    let mint_account = &ctx.accounts.mint_account;
     let payer = &mut ctx.accounts.payer;
     let token_program = &ctx.accounts.token_program;
+    let current = Clock::get()?.unix_timestamp;
+    if data.quantity == 0 {
+        return Err(SubscriptionErrors::InvalidQuantity.into());
+    }
+    // a wallet only gets the trial the first time it subscribes to the plan, the record is only
+    // needed for plans that have one;
+    let is_trial = if plan_account.trial_seconds > 0 {
+        let trial_account = trial_account.as_ref().ok_or(SubscriptionErrors::MissingTrialRecord)?;
+        trial_account.used_at == 0
+    } else {
+        false
+    };
+    // prepaid terms are billed as a single long term, so charging waits until it's over and closing
+    // refunds whatever part of it hasn't been used;
+    let prepaid_terms = data.prepaid_terms.max(1);
+    if is_trial && prepaid_terms > 1 {
+        return Err(SubscriptionErrors::PrepaidTrial.into());
+    }
     subscription_account.plan_account = plan_account.key();
     subscription_account.payer_token_account = payer_token_account.key();
-    subscription_account.owner = payer.key();
+    subscription_account.owner = ctx.accounts.beneficiary.key();
+    subscription_account.payer_authority = payer.key();
     subscription_account.state = SubscriptionState::Active;
-    subscription_account.next_term_date =
-        Clock::get()?.unix_timestamp + (plan_account.term_in_seconds as i64);
-    plan_account.active_subscriptions += 1;
+    subscription_account.plan_version = plan_account.version;
+    subscription_account.layout_version = Subscription::LAYOUT_VERSION;
+    subscription_account.quantity = data.quantity;
+    subscription_account.next_quantity = data.quantity;
+    subscription_account.delegated_allowance = data.delegation_amount;
+    subscription_account.unit_price = plan_account.unit_price;
+    subscription_account.included_units = plan_account.included_units;
+    if is_trial {
+        // nothing is escrowed for the trial, the first real charge happens at the end of it;
+        subscription_account.next_term_date = add_seconds(current, plan_account.trial_seconds)?;
+        subscription_account.term_price = 0;
+        subscription_account.term_in_seconds = plan_account.trial_seconds;
+        let trial_account = trial_account.as_mut().ok_or(SubscriptionErrors::MissingTrialRecord)?;
+        trial_account.plan_account = plan_account.key();
+        trial_account.owner = ctx.accounts.beneficiary.key();
+        trial_account.used_at = current;
+    } else {
+        subscription_account.term_in_seconds = plan_account
+            .term_in_seconds
+            .checked_mul(prepaid_terms as u64)
+            .ok_or(SubscriptionErrors::MathOverflow)?;
+        subscription_account.next_term_date =
+            add_seconds(current, subscription_account.term_in_seconds)?;
+        if prepaid_terms > 1 {
+            subscription_account.paid_through = subscription_account.next_term_date;
+        }
+    }
+    if let Some(coupon_account) = &mut ctx.accounts.coupon_account {
+        coupon_account.redeem(current)?;
+        ctx.accounts
+            .coupon_redemption
+            .as_mut()
+            .ok_or(SubscriptionErrors::MissingCouponRedemption)?
+            .record(coupon_account.key(), subscription_account.owner, current)?;
+        subscription_account.discount = coupon_account.discount.clone();
+        subscription_account.discount_terms_remaining = coupon_account.duration_terms;
+    }
+    // a trial isn't charged so it doesn't use up any of the discounted terms;
+    let mut first_price = if is_trial {
+        0
+    } else {
+        let price = plan_account.price_for(data.quantity)?;
+        subscription_account.take_discounted_terms(price, prepaid_terms)?
+    };
+    if prepaid_terms > 1 {
+        first_price -= fee_amount(first_price, plan_account.prepay_discount_bps)?;
+    }
+    if let Some(coupon_account) = &ctx.accounts.coupon_account {
+        emit!(CouponApplied {
+            plan: plan_account.key(),
+            coupon: coupon_account.key(),
+            subscription: subscription_account.key(),
+            subscriber: subscription_account.owner,
+            discount_terms_remaining: subscription_account.discount_terms_remaining,
+            timestamp: current,
+        });
+    }
+    plan_account.track_state(None, Some(&subscription_account.state))?;
     let approve_accounts = Approve {
         delegate: subscription_account.to_account_info().clone(),
         to: payer_token_account.to_account_info().clone(),
@@ -30,15 +115,38 @@ pub fn handle_create_subscription(
         CpiContext::new(token_program.to_account_info().clone(), approve_accounts),
         data.delegation_amount,
     )?;
-    let transfer_accounts = Transfer {
-        from: payer_token_account.to_account_info().clone(),
-        to: plan_token_account.to_account_info().clone(),
-        authority: payer.to_account_info().clone(),
-    };
-    transfer(
-        CpiContext::new(token_program.to_account_info().clone(), transfer_accounts),
-        plan_account.price,
-    )?;
+    if !is_trial {
+        let transfer_accounts = TransferChecked {
+            from: payer_token_account.to_account_info().clone(),
+            mint: mint_account.to_account_info().clone(),
+            to: plan_token_account.to_account_info().clone(),
+            authority: payer.to_account_info().clone(),
+        };
+        let before = plan_token_account.amount;
+        transfer_checked(
+            CpiContext::new(token_program.to_account_info().clone(), transfer_accounts),
+            first_price,
+            mint_account.decimals,
+        )?;
+        // with a transfer fee the escrow holds less than the price, so that's what gets paid out later;
+        plan_token_account.reload()?;
+        subscription_account.term_price = plan_token_account
+            .amount
+            .checked_sub(before)
+            .ok_or(SubscriptionErrors::MathOverflow)?;
+    }
+    emit!(SubscriptionCreated {
+        plan: plan_account.key(),
+        subscription: subscription_account.key(),
+        subscriber: subscription_account.owner,
+        payer_authority: subscription_account.payer_authority,
+        plan_version: subscription_account.plan_version,
+        amount: subscription_account.term_price,
+        trial: is_trial,
+        prepaid_terms,
+        next_term_date: subscription_account.next_term_date,
+        timestamp: current,
+    });
     Ok(())
 }
 
@@ -52,6 +160,15 @@ pub enum SubscriptionState {
     Active,
     PendingCancellation,
     PastDue,
+    // the grace period ran out without a successful retry;
+    Lapsed,
+    // the last term before a cancellation has ended;
+    Cancelled,
+    // the renewal was refused because the delegation doesn't cover it, the subscriber has to
+    // refresh it before the grace period runs out;
+    AllowanceExhausted,
+    // the term's clock is stopped and nothing is charged until it's resumed;
+    Paused,
 }
 
 #[account]
@@ -59,8 +176,148 @@ pub struct Subscription {
     pub plan_account: Pubkey,        // 32
     pub payer_token_account: Pubkey, // 32
     pub next_term_date: i64,         // 8
+    // the beneficiary, who holds the subscription;
     pub owner: Pubkey,               // 32
     pub state: SubscriptionState,    // 1 + 10 = 11
+    // plan version and length in force when the current term began, and what escrow received for it;
+    pub plan_version: u32,           // 4
+    pub term_price: u64,             // 8
+    pub term_in_seconds: u64,        // 8
+    pub retry_count: u8,             // 1
+    pub past_due_since: i64,         // 8
+    pub term_index: u32,             // 4
+    // coupon discount and how many more charged terms it applies to;
+    pub discount: Discount,             // 1 + 8 = 9
+    pub discount_terms_remaining: u32,  // 4
+    // usage reported for the current term, billed when it ends;
+    pub usage_units: u64,               // 8
+    // seats paid for in the current term, and for the next one when a decrease is pending;
+    pub quantity: u32,                  // 4
+    pub next_quantity: u32,             // 4
+    // owns payer_token_account and approved the delegation, the same as owner unless sponsored;
+    pub payer_authority: Pubkey,        // 32
+    // paid for up front by payer_authority, payer_token_account is then a vault owned by the
+    // subscription that the remaining terms are charged from;
+    pub gifted: bool,                   // 1
+    // end of the terms paid for up front, zero if none were;
+    pub paid_through: i64,              // 8
+    // last allowance approved to the subscription, the token account's delegated amount is
+    // what's left of it;
+    pub delegated_allowance: u64,       // 8
+    // cancelled by the plan owner, which the subscriber can't undo;
+    pub merchant_cancelled: bool,       // 1
+    // when the current pause began, and the pauses taken in the year starting at pause_window_start;
+    pub paused_at: i64,                 // 8
+    pub pause_window_start: i64,        // 8
+    pub pauses_in_window: u8,           // 1
+    // same as on Plan, migrate_subscription fills in fields added after this layout, fixed-size
+    // ones are taken out of reserved;
+    pub layout_version: u8,             // 1
+    // the plan's usage pricing when the current term began, usage is billed at it when it ends;
+    pub unit_price: u64,                // 8
+    pub included_units: u64,            // 8
+    pub reserved: [u8; 48],             // 64 - 16 = 48
+}
+
+impl Subscription {
+    pub const LAYOUT_VERSION: u8 = 2;
+    pub const SPACE: usize =
+        8 + 32 + 32 + 32 + 8 + 11 + 4 + 8 + 8 + 1 + 8 + 4 + 9 + 4 + 8 + 4 + 4 + 32 + 1 + 8 + 8 + 1
+            + 8 + 8 + 1 + 1 + 8 + 8 + 48;
+
+    // moves to `state` and keeps the plan's counters in step;
+    pub fn transition(&mut self, plan: &mut Plan, state: SubscriptionState) -> Result<()> {
+        plan.track_state(Some(&self.state), Some(&state))?;
+        self.state = state;
+        Ok(())
+    }
+
+    // how far into the current term it is at `current`, which stops moving while paused;
+    pub fn term_clock(&self, current: i64) -> i64 {
+        if self.state == SubscriptionState::Paused {
+            return self.paused_at;
+        }
+        current
+    }
+
+    // when the longest pause the plan allows runs out, if it has a limit;
+    pub fn pause_ends(&self, plan: &Plan) -> Result<Option<i64>> {
+        if plan.max_pause_seconds == 0 {
+            return Ok(None);
+        }
+        Ok(Some(add_seconds(self.paused_at, plan.max_pause_seconds)?))
+    }
+
+    // ends the pause at `at`, the term picks up with whatever was left of it when it was paused;
+    pub fn resume(&mut self, plan: &mut Plan, at: i64) -> Result<()> {
+        let remainder = (self.next_term_date - self.paused_at).max(0);
+        let shift = at + remainder - self.next_term_date;
+        self.next_term_date += shift;
+        if self.paid_through != 0 {
+            self.paid_through += shift;
+        }
+        self.paused_at = 0;
+        self.transition(plan, SubscriptionState::Active)
+    }
+
+    // the usage pricing the current term is billed at; accounts from before it was kept on the
+    // subscription are billed at the plan's until they're migrated;
+    pub fn usage_pricing(&self, plan: &Plan) -> (u64, u64) {
+        if self.layout_version < 2 {
+            return (plan.unit_price, plan.included_units);
+        }
+        (self.unit_price, self.included_units)
+    }
+
+    // what the usage reported in the current term costs on top of the price;
+    pub fn overage(&self, plan: &Plan) -> Result<u64> {
+        let (unit_price, included_units) = self.usage_pricing(plan);
+        self.usage_units
+            .saturating_sub(included_units)
+            .checked_mul(unit_price)
+            .ok_or(SubscriptionErrors::MathOverflow.into())
+    }
+
+    // the price of the next charged term;
+    pub fn discounted_price(&self, price: u64) -> Result<u64> {
+        if self.discount_terms_remaining == 0 {
+            return Ok(price);
+        }
+        self.discount.apply(price)
+    }
+
+    // same as discounted_price but uses up one of the discounted terms;
+    pub fn take_discounted_price(&mut self, price: u64) -> Result<u64> {
+        self.take_discounted_terms(price, 1)
+    }
+
+    // the total for `terms` charged terms, using up as many discounted terms as it covers;
+    pub fn take_discounted_terms(&mut self, price: u64, terms: u32) -> Result<u64> {
+        let discounted_terms = self.discount_terms_remaining.min(terms);
+        let discounted = self
+            .discounted_price(price)?
+            .checked_mul(discounted_terms as u64)
+            .ok_or(SubscriptionErrors::MathOverflow)?;
+        let total = price
+            .checked_mul((terms - discounted_terms) as u64)
+            .and_then(|full| full.checked_add(discounted))
+            .ok_or(SubscriptionErrors::MathOverflow)?;
+        if discounted_terms > 0 {
+            self.discount_terms_remaining -= discounted_terms;
+            if self.discount_terms_remaining == 0 {
+                self.discount = Discount::None;
+            }
+        }
+        Ok(total)
+    }
+}
+
+// outlives the subscription so a wallet can't close and resubscribe for another trial;
+#[account]
+pub struct TrialRecord {
+    pub plan_account: Pubkey, // 32
+    pub owner: Pubkey,        // 32
+    pub used_at: i64,         // 8
 }
 
 #[derive(Accounts)]
@@ -68,36 +325,68 @@ pub struct CreateSubscriptionParams<'info> {
     #[account(
         init, 
         payer = payer, 
-        space =  8 + 32 + 32 + 32 + 8 + 11,
-        seeds = [b"subscription".as_ref(), payer.key().as_ref(), plan_account.key().as_ref()],
+        space = Subscription::SPACE,
+        seeds = [b"subscription".as_ref(), beneficiary.key().as_ref(), plan_account.key().as_ref()],
         bump,
     )]
     pub subscription_account: Account<'info, Subscription>,
     #[account(
         mut,
-        seeds = [b"plan".as_ref(), plan_account.owner.key().as_ref(), plan_account.code.as_ref()],
+        seeds = [b"plan".as_ref(), plan_account.owner.key().as_ref(), plan_account.code_seed()],
+        constraint = plan_account.state == PlanState::Active @ SubscriptionErrors::PlanInactive,
         bump,
     )]
     pub plan_account: Account<'info, Plan>,
     #[account(
         mut,
-        constraint = payer_token_account.mint == plan_account.token_mint,
-        constraint = payer_token_account.owner == payer.key(),
+        constraint = payer_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = payer_token_account.owner == payer.key() @ SubscriptionErrors::InvalidTokenAccountOwner,
     )]
-    pub payer_token_account: Account<'info, TokenAccount>,
+    pub payer_token_account: InterfaceAccount<'info, TokenAccount>,
     #[account(
         mut,
-        constraint = plan_token_account.mint == plan_account.token_mint,
-        constraint = plan_token_account.owner == plan_account.key(),
+        constraint = plan_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = plan_token_account.owner == plan_account.key() @ SubscriptionErrors::InvalidTokenAccountOwner,
+    )]
+    pub plan_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(address = plan_account.token_mint @ SubscriptionErrors::MintMismatch)]
+    pub mint_account: InterfaceAccount<'info, Mint>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + 32 + 32 + 8,
+        seeds = [b"trial".as_ref(), beneficiary.key().as_ref(), plan_account.key().as_ref()],
+        bump,
+    )]
+    pub trial_account: Option<Account<'info, TrialRecord>>,
+    #[account(
+        mut,
+        seeds = [b"coupon".as_ref(), plan_account.key().as_ref(), coupon_account.code.as_ref()],
+        bump,
+    )]
+    pub coupon_account: Option<Account<'info, Coupon>>,
+    // only needed with a coupon;
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = CouponRedemption::SPACE,
+        seeds = [b"coupon_redemption".as_ref(), coupon_account.as_ref().map(|coupon| coupon.key()).unwrap_or_default().as_ref(), beneficiary.key().as_ref()],
+        bump,
     )]
-    pub plan_token_account: Account<'info, TokenAccount>,
+    pub coupon_redemption: Option<Account<'info, CouponRedemption>>,
+    // signs as well so no one is subscribed, and has their trial used up, without agreeing to it;
+    // the same wallet as the payer unless sponsored;
+    pub beneficiary: Signer<'info>,
     #[account(mut)]
     pub payer: Signer<'info>,
     pub system_program: Program<'info, System>,
-    pub token_program: Program<'info, Token>,
+    pub token_program: Interface<'info, TokenInterface>,
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Debug)]
 pub struct CreateSubscriptionData {
     pub delegation_amount: u64,
+    pub quantity: u32,
+    // terms to pay for up front at the plan's prepay discount, zero or one for a single term;
+    pub prepaid_terms: u32,
 }
@@ -0,0 +1,227 @@
+use anchor_lang::prelude::*;
+use anchor_spl::{
+    token_2022::spl_token_2022::{
+        extension::{
+            transfer_fee::TransferFeeConfig, BaseStateWithExtensions, StateWithExtensions,
+        },
+        state::Mint as MintState,
+    },
+    token_interface::{
+        close_account, transfer_checked, CloseAccount, Mint, TokenAccount, TokenInterface,
+        TransferChecked,
+    },
+};
+
+use crate::{events::SubscriptionGifted, math::add_seconds, SubscriptionErrors};
+
+use super::{
+    create_plan::{Plan, PlanState},
+    create_subscription::{Subscription, SubscriptionState},
+};
+
+pub fn handle_gift_subscription(
+    ctx: Context<GiftSubscriptionParams>,
+    data: GiftSubscriptionData,
+) -> Result<()> {
+    // the gifter pays for every term up front: the first goes to the plan escrow like any new
+    // subscription and the rest waits in a vault that charges draw from instead of a delegation;
+    // once the vault can't cover a term, e.g. after a price increase, the gift has run out and
+    // ends like a cancellation;
+    let plan_account = &mut ctx.accounts.plan_account;
+    let subscription_account = &mut ctx.accounts.subscription_account;
+    let plan_token_account = &mut ctx.accounts.plan_token_account;
+    let gift_vault = &mut ctx.accounts.gift_vault;
+    let payer_token_account = &ctx.accounts.payer_token_account;
+    let mint_account = &ctx.accounts.mint_account;
+    let payer = &ctx.accounts.payer;
+    let token_program = &ctx.accounts.token_program;
+    let current = Clock::get()?.unix_timestamp;
+    if data.terms == 0 {
+        return Err(SubscriptionErrors::InvalidGiftTerms.into());
+    }
+
+    subscription_account.plan_account = plan_account.key();
+    subscription_account.payer_token_account = gift_vault.key();
+    subscription_account.owner = ctx.accounts.beneficiary.key();
+    subscription_account.payer_authority = payer.key();
+    subscription_account.gifted = true;
+    subscription_account.state = SubscriptionState::Active;
+    subscription_account.plan_version = plan_account.version;
+    subscription_account.layout_version = Subscription::LAYOUT_VERSION;
+    subscription_account.next_term_date = add_seconds(current, plan_account.term_in_seconds)?;
+    subscription_account.term_in_seconds = plan_account.term_in_seconds;
+    subscription_account.quantity = 1;
+    subscription_account.next_quantity = 1;
+    subscription_account.unit_price = plan_account.unit_price;
+    subscription_account.included_units = plan_account.included_units;
+    plan_account.track_state(None, Some(&subscription_account.state))?;
+
+    let transfer_accounts = TransferChecked {
+        from: payer_token_account.to_account_info().clone(),
+        mint: mint_account.to_account_info().clone(),
+        to: plan_token_account.to_account_info().clone(),
+        authority: payer.to_account_info().clone(),
+    };
+    let before = plan_token_account.amount;
+    transfer_checked(
+        CpiContext::new(token_program.to_account_info().clone(), transfer_accounts),
+        plan_account.price,
+        mint_account.decimals,
+    )?;
+    plan_token_account.reload()?;
+    subscription_account.term_price = plan_token_account
+        .amount
+        .checked_sub(before)
+        .ok_or(SubscriptionErrors::MathOverflow)?;
+
+    // the vault has to end up with the full price of each remaining term, so a transfer fee is
+    // paid on top;
+    let vault_amount = plan_account
+        .price
+        .checked_mul((data.terms - 1) as u64)
+        .ok_or(SubscriptionErrors::MathOverflow)?;
+    if vault_amount > 0 {
+        let sent = with_transfer_fee(&mint_account.to_account_info(), vault_amount)?;
+        let transfer_accounts = TransferChecked {
+            from: payer_token_account.to_account_info().clone(),
+            mint: mint_account.to_account_info().clone(),
+            to: gift_vault.to_account_info().clone(),
+            authority: payer.to_account_info().clone(),
+        };
+        transfer_checked(
+            CpiContext::new(token_program.to_account_info().clone(), transfer_accounts),
+            sent,
+            mint_account.decimals,
+        )?;
+        gift_vault.reload()?;
+    }
+    emit!(SubscriptionGifted {
+        plan: plan_account.key(),
+        subscription: subscription_account.key(),
+        subscriber: subscription_account.owner,
+        gifter: payer.key(),
+        terms: data.terms,
+        amount: subscription_account.term_price,
+        vault_amount: gift_vault.amount,
+        next_term_date: subscription_account.next_term_date,
+        timestamp: current,
+    });
+    Ok(())
+}
+
+// what has to be sent for `amount` to arrive, on top of it goes the mint's transfer fee if it has
+// one;
+fn with_transfer_fee(mint_account: &AccountInfo, amount: u64) -> Result<u64> {
+    let data = mint_account.try_borrow_data()?;
+    let mint = StateWithExtensions::<MintState>::unpack(&data)?;
+    let fee_config = match mint.get_extension::<TransferFeeConfig>() {
+        Ok(fee_config) => fee_config,
+        Err(_) => return Ok(amount),
+    };
+    fee_config
+        .calculate_inverse_epoch_fee(Clock::get()?.epoch, amount)
+        .and_then(|fee| amount.checked_add(fee))
+        .ok_or(SubscriptionErrors::MathOverflow.into())
+}
+
+// the vault holding a gift's remaining terms, only the subscription that owns it can sign for it;
+pub(crate) struct GiftVault<'a, 'info> {
+    pub token_program: AccountInfo<'info>,
+    pub subscription_account: AccountInfo<'info>,
+    pub vault: AccountInfo<'info>,
+    pub mint_account: AccountInfo<'info>,
+    pub decimals: u8,
+    pub subscription_seeds: &'a [&'a [u8]],
+}
+
+impl<'a, 'info> GiftVault<'a, 'info> {
+    // sends `amount`, whatever is left in the vault, back to the gifter and closes it;
+    pub fn refund_and_close(
+        &self,
+        amount: u64,
+        to: &AccountInfo<'info>,
+        rent_to: &AccountInfo<'info>,
+    ) -> Result<()> {
+        let transfer_accounts = TransferChecked {
+            from: self.vault.clone(),
+            mint: self.mint_account.clone(),
+            to: to.clone(),
+            authority: self.subscription_account.clone(),
+        };
+        transfer_checked(
+            CpiContext::new_with_signer(
+                self.token_program.clone(),
+                transfer_accounts,
+                &[self.subscription_seeds],
+            ),
+            amount,
+            self.decimals,
+        )?;
+        let close_accounts = CloseAccount {
+            account: self.vault.clone(),
+            destination: rent_to.clone(),
+            authority: self.subscription_account.clone(),
+        };
+        close_account(CpiContext::new_with_signer(
+            self.token_program.clone(),
+            close_accounts,
+            &[self.subscription_seeds],
+        ))
+    }
+}
+
+#[derive(Accounts)]
+pub struct GiftSubscriptionParams<'info> {
+    #[account(
+        init,
+        payer = payer,
+        space = Subscription::SPACE,
+        seeds = [b"subscription".as_ref(), beneficiary.key().as_ref(), plan_account.key().as_ref()],
+        bump,
+    )]
+    pub subscription_account: Account<'info, Subscription>,
+    #[account(
+        init,
+        payer = payer,
+        seeds = [b"gift_vault".as_ref(), subscription_account.key().as_ref()],
+        bump,
+        token::mint = mint_account,
+        token::authority = subscription_account,
+        token::token_program = token_program,
+    )]
+    pub gift_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        seeds = [b"plan".as_ref(), plan_account.owner.key().as_ref(), plan_account.code_seed()],
+        constraint = plan_account.state == PlanState::Active @ SubscriptionErrors::PlanInactive,
+        bump,
+    )]
+    pub plan_account: Account<'info, Plan>,
+    #[account(
+        mut,
+        constraint = plan_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = plan_token_account.owner == plan_account.key() @ SubscriptionErrors::InvalidTokenAccountOwner,
+    )]
+    pub plan_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = payer_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = payer_token_account.owner == payer.key() @ SubscriptionErrors::InvalidTokenAccountOwner,
+    )]
+    pub payer_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(address = plan_account.token_mint @ SubscriptionErrors::MintMismatch)]
+    pub mint_account: InterfaceAccount<'info, Mint>,
+    // signs as well so no one's subscription address, and with it their trial, is taken by a gift
+    // they didn't agree to;
+    pub beneficiary: Signer<'info>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Debug)]
+pub struct GiftSubscriptionData {
+    // how many terms the gift pays for, including the first one;
+    pub terms: u32,
+}
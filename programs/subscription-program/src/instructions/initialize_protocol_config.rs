@@ -0,0 +1,59 @@
+use anchor_lang::prelude::*;
+
+use crate::{math::RoundingPolicy, program::SubscriptionProgram, SubscriptionErrors};
+
+pub const MAX_FEE_BPS: u16 = 10_000;
+
+pub fn handle_initialize_protocol_config(
+    ctx: Context<InitializeProtocolConfigParams>,
+    data: InitializeProtocolConfigData,
+) -> Result<()> {
+    if data.fee_bps > MAX_FEE_BPS {
+        return Err(SubscriptionErrors::InvalidFee.into());
+    }
+    let protocol_config = &mut ctx.accounts.protocol_config;
+    protocol_config.authority = ctx.accounts.payer.key();
+    protocol_config.fee_bps = data.fee_bps;
+    protocol_config.fee_recipient = data.fee_recipient;
+    protocol_config.rounding = data.rounding;
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct InitializeProtocolConfigParams<'info> {
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + 32 + 2 + 32 + 1,
+        seeds = [b"protocol_config".as_ref()],
+        bump,
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    // only the upgrade authority of the program can create the config;
+    #[account(
+        constraint = program.programdata_address()? == Some(program_data.key()) @ SubscriptionErrors::Unauthorized,
+    )]
+    pub program: Program<'info, SubscriptionProgram>,
+    #[account(
+        constraint = program_data.upgrade_authority_address == Some(payer.key()) @ SubscriptionErrors::Unauthorized,
+    )]
+    pub program_data: Account<'info, ProgramData>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Debug)]
+pub struct InitializeProtocolConfigData {
+    pub fee_bps: u16,
+    pub fee_recipient: Pubkey,
+    pub rounding: RoundingPolicy,
+}
+
+#[account]
+pub struct ProtocolConfig {
+    pub authority: Pubkey,        // 32
+    pub fee_bps: u16,             // 2
+    pub fee_recipient: Pubkey,    // 32
+    pub rounding: RoundingPolicy, // 1
+}
@@ -0,0 +1,243 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
+
+use crate::{events::SubscriptionCancelledByMerchant, math::split_fee, SubscriptionErrors};
+
+use super::{
+    charge_subscription::PlanEscrow,
+    close_subscription::unused_term_credit,
+    create_plan::Plan,
+    create_subscription::{Subscription, SubscriptionState},
+    gift_subscription::GiftVault,
+    initialize_protocol_config::ProtocolConfig,
+};
+
+pub fn handle_merchant_cancel_subscription(
+    ctx: Context<MerchantCancelSubscriptionParams>,
+    data: MerchantCancelData,
+) -> Result<()> {
+    // the plan owner can end any live subscription, at the end of the term or straight away with a
+    // refund; only the token account owner can revoke a delegation, so ending it straight away
+    // closes the subscription instead, which leaves the approval with nothing that can sign for it;
+    let subscription_account = &mut ctx.accounts.subscription_account;
+    let plan_account = &mut ctx.accounts.plan_account;
+    let plan_token_account = &ctx.accounts.plan_token_account;
+    let payer_token_account = &ctx.accounts.payer_token_account;
+    let owner_token_account = &ctx.accounts.owner_token_account;
+    let deployer_token_account = &ctx.accounts.deployer_token_account;
+    let protocol_config = &ctx.accounts.protocol_config;
+    let mint_account = &ctx.accounts.mint_account;
+    let token_program = &ctx.accounts.token_program;
+    let current = Clock::get()?.unix_timestamp;
+    let mut refund = 0;
+    let mut usage = 0;
+    let mut owner_amount = 0;
+    let mut tax = 0;
+
+    if data.mode == MerchantCancelMode::EndOfTerm {
+        // finalized by the charge at the end of the term like any other cancellation, but the
+        // subscriber can't undo it; a paused term is resumed first so it still ends with the rest
+        // of it served;
+        if subscription_account.state == SubscriptionState::Paused {
+            subscription_account.resume(plan_account, current)?;
+        }
+        subscription_account.merchant_cancelled = true;
+        subscription_account.transition(plan_account, SubscriptionState::PendingCancellation)?;
+    } else {
+        plan_account.track_state(Some(&subscription_account.state), None)?;
+        refund = match data.mode {
+            MerchantCancelMode::ProratedRefund => {
+                let term_clock = subscription_account.term_clock(current);
+                unused_term_credit(subscription_account, term_clock, protocol_config.rounding)?
+            }
+            _ => subscription_account.term_price,
+        };
+        let plan_account_owner_key = plan_account.owner.key();
+        let (_pda, bump) = Pubkey::find_program_address(
+            &[
+                b"plan".as_ref(),
+                plan_account_owner_key.as_ref(),
+                plan_account.code_seed(),
+            ],
+            ctx.program_id,
+        );
+        let escrow = PlanEscrow {
+            token_program: token_program.to_account_info(),
+            plan_account: plan_account.to_account_info(),
+            plan_token_account: plan_token_account.to_account_info(),
+            mint_account: mint_account.to_account_info(),
+            decimals: mint_account.decimals,
+            plan_seeds: &[
+                b"plan".as_ref(),
+                plan_account_owner_key.as_ref(),
+                plan_account.code_seed(),
+                &[bump],
+            ],
+        };
+        // the term's usage is billed now, as it is when the subscriber closes early;
+        let usage_source = if subscription_account.gifted {
+            ctx.accounts
+                .gift_vault
+                .as_ref()
+                .ok_or(SubscriptionErrors::MissingGiftVault)?
+        } else {
+            payer_token_account
+        };
+        usage = escrow.collect_final_usage(
+            plan_account,
+            subscription_account,
+            usage_source,
+            ctx.program_id,
+        )?;
+        escrow.transfer_from_escrow(&payer_token_account.to_account_info(), refund)?;
+        let used = subscription_account
+            .term_price
+            .checked_sub(refund)
+            .and_then(|used| used.checked_add(usage))
+            .ok_or(SubscriptionErrors::MathOverflow)?;
+        (owner_amount, tax) = split_fee(used, protocol_config.fee_bps)?;
+        escrow.transfer_from_escrow(&owner_token_account.to_account_info(), owner_amount)?;
+        escrow.transfer_from_escrow(&deployer_token_account.to_account_info(), tax)?;
+
+        if subscription_account.gifted {
+            let gift_vault = ctx
+                .accounts
+                .gift_vault
+                .as_mut()
+                .ok_or(SubscriptionErrors::MissingGiftVault)?;
+            gift_vault.reload()?;
+            let subscription_owner_key = subscription_account.owner.key();
+            let plan_key = plan_account.key();
+            let (_pda, subscription_bump) = Pubkey::find_program_address(
+                &[
+                    b"subscription".as_ref(),
+                    subscription_owner_key.as_ref(),
+                    plan_key.as_ref(),
+                ],
+                ctx.program_id,
+            );
+            let vault = GiftVault {
+                token_program: token_program.to_account_info(),
+                subscription_account: subscription_account.to_account_info(),
+                vault: gift_vault.to_account_info(),
+                mint_account: mint_account.to_account_info(),
+                decimals: mint_account.decimals,
+                subscription_seeds: &[
+                    b"subscription".as_ref(),
+                    subscription_owner_key.as_ref(),
+                    plan_key.as_ref(),
+                    &[subscription_bump],
+                ],
+            };
+            vault.refund_and_close(
+                gift_vault.amount,
+                &payer_token_account.to_account_info(),
+                &ctx.accounts.payer_authority.to_account_info(),
+            )?;
+            refund = refund
+                .checked_add(gift_vault.amount)
+                .ok_or(SubscriptionErrors::MathOverflow)?;
+        }
+        plan_account.record_payout(owner_amount, tax)?;
+        plan_account.record_refund(refund)?;
+    }
+    emit!(SubscriptionCancelledByMerchant {
+        plan: plan_account.key(),
+        subscription: subscription_account.key(),
+        subscriber: subscription_account.owner,
+        term_index: subscription_account.term_index,
+        mode: data.mode.clone(),
+        reason_code: data.reason_code,
+        refund,
+        usage_amount: usage,
+        owner_amount,
+        fee: tax,
+        timestamp: current,
+    });
+    if data.mode != MerchantCancelMode::EndOfTerm {
+        subscription_account.close(ctx.accounts.payer_authority.to_account_info())?;
+    }
+    Ok(())
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Debug, PartialEq)]
+pub enum MerchantCancelMode {
+    // runs out the current term, nothing is refunded;
+    #[default]
+    EndOfTerm,
+    // ends now, the unused part of the term is refunded and the rest paid out;
+    ProratedRefund,
+    // ends now, everything escrowed for the term is refunded;
+    FullRefund,
+}
+
+#[derive(Accounts)]
+pub struct MerchantCancelSubscriptionParams<'info> {
+    #[account(
+        mut,
+        seeds = [b"subscription".as_ref(), subscription_account.owner.key().as_ref(), plan_account.key().as_ref()],
+        constraint = subscription_account.state != SubscriptionState::Lapsed @ SubscriptionErrors::InvalidSubscriptionState,
+        constraint = subscription_account.state != SubscriptionState::Cancelled @ SubscriptionErrors::InvalidSubscriptionState,
+        bump,
+    )]
+    pub subscription_account: Account<'info, Subscription>,
+    #[account(
+        mut,
+        seeds = [b"plan".as_ref(), plan_account.owner.key().as_ref(), plan_account.code_seed()],
+        constraint = plan_account.owner == payer.key() @ SubscriptionErrors::Unauthorized,
+        bump,
+    )]
+    pub plan_account: Account<'info, Plan>,
+    #[account(
+        mut,
+        constraint = plan_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = plan_token_account.owner == plan_account.key() @ SubscriptionErrors::InvalidTokenAccountOwner,
+    )]
+    pub plan_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = payer_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = payer_token_account.owner == subscription_account.payer_authority @ SubscriptionErrors::InvalidTokenAccountOwner,
+        // a gift's own token account is its vault, the rest of it goes back to the gifter's;
+        constraint = subscription_account.gifted || payer_token_account.key() == subscription_account.payer_token_account @ SubscriptionErrors::TokenAccountMismatch,
+    )]
+    pub payer_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = owner_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = owner_token_account.owner == plan_account.owner.key() @ SubscriptionErrors::InvalidTokenAccountOwner,
+    )]
+    pub owner_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = deployer_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = deployer_token_account.owner == protocol_config.fee_recipient @ SubscriptionErrors::InvalidTokenAccountOwner,
+    )]
+    pub deployer_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        seeds = [b"protocol_config".as_ref()],
+        bump,
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(
+        mut,
+        address = subscription_account.payer_token_account @ SubscriptionErrors::TokenAccountMismatch,
+    )]
+    pub gift_vault: Option<InterfaceAccount<'info, TokenAccount>>,
+    #[account(address = plan_account.token_mint @ SubscriptionErrors::MintMismatch)]
+    pub mint_account: InterfaceAccount<'info, Mint>,
+    /// CHECK: paid the subscription's rent and gets it back when it ends straight away
+    #[account(mut, address = subscription_account.payer_authority @ SubscriptionErrors::PayerAuthorityMismatch)]
+    pub payer_authority: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Debug)]
+pub struct MerchantCancelData {
+    pub mode: MerchantCancelMode,
+    // the merchant's own code for why, only recorded on the event;
+    pub reason_code: u16,
+}
@@ -0,0 +1,95 @@
+use anchor_lang::{
+    prelude::*,
+    system_program::{transfer, Transfer},
+    Discriminator,
+};
+
+use crate::{events::PlanMigrated, math::add_seconds, SubscriptionErrors};
+
+use super::create_plan::Plan;
+
+pub fn handle_migrate_plan(ctx: Context<MigratePlanParams>) -> Result<()> {
+    // anyone can bring a plan written with an older layout up to date, paying for the extra rent;
+    let plan_info = ctx.accounts.plan_account.to_account_info();
+    grow_account(
+        &plan_info,
+        &Plan::DISCRIMINATOR,
+        Plan::SPACE,
+        &ctx.accounts.payer.to_account_info(),
+        &ctx.accounts.system_program.to_account_info(),
+    )?;
+    let mut plan_account = Account::<Plan>::try_from(&plan_info)?;
+    let from_version = plan_account.layout_version;
+    if from_version >= Plan::LAYOUT_VERSION {
+        return Err(SubscriptionErrors::AlreadyMigrated.into());
+    }
+    let current = Clock::get()?.unix_timestamp;
+    upgrade(&mut plan_account, from_version, current);
+    plan_account.layout_version = Plan::LAYOUT_VERSION;
+    plan_account.exit(ctx.program_id)?;
+    emit!(PlanMigrated {
+        plan: plan_info.key(),
+        from_version,
+        to_version: Plan::LAYOUT_VERSION,
+        timestamp: current,
+    });
+    Ok(())
+}
+
+// fields added after from_version are set to their defaults here; plans from before version 2
+// are left without a code hash, their address is still derived from the code itself;
+pub(crate) fn upgrade(plan: &mut Plan, from_version: u8, current: i64) {
+    if from_version == 0 {
+        // the original program counted every subscription ever created as active and never took
+        // any back off, so they're all carried as unmigrated, which keeps close_plan from draining
+        // their escrow, and migrate_subscription counts each one again; the original program only
+        // charged a term once it was due, so none of them is paid for past one term from now;
+        plan.unmigrated_subscriptions = plan.active_subscriptions;
+        plan.unmigrated_terms_end = add_seconds(current, plan.term_in_seconds).unwrap_or(i64::MAX);
+        plan.active_subscriptions = 0;
+    }
+}
+
+// reallocs a program account to `space`, topping up its rent from `payer`; fields are only ever
+// appended, so whatever an older layout didn't have reads as zero afterwards;
+pub(crate) fn grow_account<'info>(
+    account: &AccountInfo<'info>,
+    discriminator: &[u8; 8],
+    space: usize,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+) -> Result<()> {
+    if account.try_borrow_data()?.get(..8) != Some(discriminator.as_ref()) {
+        return Err(ErrorCode::AccountDiscriminatorMismatch.into());
+    }
+    if account.data_len() >= space {
+        return Ok(());
+    }
+    let rent = Rent::get()?
+        .minimum_balance(space)
+        .saturating_sub(account.lamports());
+    if rent > 0 {
+        transfer(
+            CpiContext::new(
+                system_program.clone(),
+                Transfer {
+                    from: payer.clone(),
+                    to: account.clone(),
+                },
+            ),
+            rent,
+        )?;
+    }
+    account.realloc(space, true)?;
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct MigratePlanParams<'info> {
+    /// CHECK: may be on a layout that no longer deserializes, grow_account checks the discriminator
+    #[account(mut, owner = crate::ID)]
+    pub plan_account: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
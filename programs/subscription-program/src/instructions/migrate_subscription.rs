@@ -0,0 +1,232 @@
+use anchor_lang::{prelude::*, Discriminator};
+
+use crate::{events::SubscriptionMigrated, SubscriptionErrors};
+
+use super::{
+    create_plan::Plan,
+    create_subscription::{Subscription, SubscriptionState},
+    migrate_plan::grow_account,
+};
+
+pub fn handle_migrate_subscription(ctx: Context<MigrateSubscriptionParams>) -> Result<()> {
+    // anyone can bring a subscription written with an older layout up to date, paying for the
+    // extra rent;
+    let subscription_info = ctx.accounts.subscription_account.to_account_info();
+    grow_account(
+        &subscription_info,
+        &Subscription::DISCRIMINATOR,
+        Subscription::SPACE,
+        &ctx.accounts.payer.to_account_info(),
+        &ctx.accounts.system_program.to_account_info(),
+    )?;
+    let mut subscription_account = Account::<Subscription>::try_from(&subscription_info)?;
+    let from_version = subscription_account.layout_version;
+    if from_version >= Subscription::LAYOUT_VERSION {
+        return Err(SubscriptionErrors::AlreadyMigrated.into());
+    }
+    let plan_account = &mut ctx.accounts.plan_account;
+    if subscription_account.plan_account != plan_account.key() {
+        return Err(SubscriptionErrors::PlanMismatch.into());
+    }
+    upgrade(&mut subscription_account, plan_account, from_version)?;
+    subscription_account.layout_version = Subscription::LAYOUT_VERSION;
+    subscription_account.exit(ctx.program_id)?;
+    emit!(SubscriptionMigrated {
+        subscription: subscription_info.key(),
+        plan: subscription_account.plan_account,
+        from_version,
+        to_version: Subscription::LAYOUT_VERSION,
+        timestamp: Clock::get()?.unix_timestamp,
+    });
+    Ok(())
+}
+
+pub(crate) fn upgrade(
+    subscription: &mut Subscription,
+    plan: &mut Plan,
+    from_version: u8,
+) -> Result<()> {
+    if from_version == 0 {
+        // the original program had a single seat at the plan's price and length, paid for by the
+        // owner; every charge escrowed the next term before paying out the last one, so a term's
+        // price is always in escrow, even after a failed charge;
+        subscription.payer_authority = subscription.owner;
+        subscription.quantity = 1;
+        subscription.next_quantity = 1;
+        subscription.term_in_seconds = plan.term_in_seconds;
+        subscription.term_price = plan.price;
+        // a failed charge only flagged it past due without a retry schedule; going back to active
+        // lets the next charge settle the escrowed term and start dunning from there;
+        if subscription.state == SubscriptionState::PastDue {
+            subscription.state = SubscriptionState::Active;
+        }
+        // migrate_plan started the plan's counters over;
+        plan.unmigrated_subscriptions = plan.unmigrated_subscriptions.saturating_sub(1);
+        plan.track_state(None, Some(&subscription.state))?;
+    }
+    if from_version < 2 {
+        // the term in progress was started at the plan's usage pricing, which is the one it would
+        // have been billed at so far;
+        subscription.unit_price = plan.unit_price;
+        subscription.included_units = plan.included_units;
+    }
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct MigrateSubscriptionParams<'info> {
+    /// CHECK: may be on a layout that no longer deserializes, grow_account checks the discriminator
+    #[account(mut, owner = crate::ID)]
+    pub subscription_account: UncheckedAccount<'info>,
+    #[account(
+        mut,
+        seeds = [b"plan".as_ref(), plan_account.owner.key().as_ref(), plan_account.code_seed()],
+        constraint = plan_account.layout_version == Plan::LAYOUT_VERSION @ SubscriptionErrors::PlanNotMigrated,
+        bump,
+    )]
+    pub plan_account: Account<'info, Plan>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instructions::migrate_plan;
+
+    // the accounts as the original program wrote them;
+    #[derive(AnchorSerialize)]
+    struct UnversionedPlan {
+        code: String,
+        owner: Pubkey,
+        price: u64,
+        token_mint: Pubkey,
+        term_in_seconds: u64,
+        active_subscriptions: u32,
+    }
+
+    #[derive(AnchorSerialize)]
+    struct UnversionedSubscription {
+        plan_account: Pubkey,
+        payer_token_account: Pubkey,
+        next_term_date: i64,
+        owner: Pubkey,
+        state: u8,
+    }
+
+    // what grow_account leaves behind: the old data zero-padded to the current space;
+    fn grown<T: AnchorSerialize>(discriminator: [u8; 8], account: &T, space: usize) -> Vec<u8> {
+        let mut data = discriminator.to_vec();
+        account.serialize(&mut data).unwrap();
+        assert!(data.len() <= space);
+        data.resize(space, 0);
+        data
+    }
+
+    fn migrated_plan() -> Plan {
+        let data = grown(
+            Plan::DISCRIMINATOR,
+            &UnversionedPlan {
+                code: "basic".to_string(),
+                owner: Pubkey::new_unique(),
+                price: 1_000_000,
+                token_mint: Pubkey::new_unique(),
+                term_in_seconds: 30 * 86_400,
+                active_subscriptions: 7,
+            },
+            Plan::SPACE,
+        );
+        let mut plan = Plan::try_deserialize(&mut data.as_slice()).unwrap();
+        assert_eq!(plan.layout_version, 0);
+        migrate_plan::upgrade(&mut plan, 0, 1_700_000_000);
+        plan
+    }
+
+    fn migrated_subscription(plan: &mut Plan, state: u8) -> Subscription {
+        let owner = Pubkey::new_unique();
+        let data = grown(
+            Subscription::DISCRIMINATOR,
+            &UnversionedSubscription {
+                plan_account: Pubkey::new_unique(),
+                payer_token_account: Pubkey::new_unique(),
+                next_term_date: 1_700_000_000,
+                owner,
+                state,
+            },
+            Subscription::SPACE,
+        );
+        let mut subscription = Subscription::try_deserialize(&mut data.as_slice()).unwrap();
+        assert_eq!(subscription.layout_version, 0);
+        assert_eq!(subscription.owner, owner);
+        upgrade(&mut subscription, plan, 0).unwrap();
+        subscription
+    }
+
+    #[test]
+    fn unversioned_plan_starts_its_counters_over() {
+        let plan = migrated_plan();
+        assert_eq!(plan.code, "basic");
+        assert_eq!(plan.price, 1_000_000);
+        assert_eq!(plan.term_in_seconds, 30 * 86_400);
+        assert_eq!(plan.active_subscriptions, 0);
+        assert_eq!(plan.unmigrated_subscriptions, 7);
+        assert_eq!(plan.unmigrated_terms_end, 1_700_000_000 + 30 * 86_400);
+        assert_eq!(plan.live_subscriptions(), 7);
+        assert_eq!(plan.code_hash, [0; 32]);
+        assert!(plan.name.is_empty());
+    }
+
+    #[test]
+    fn unversioned_subscription_is_backfilled_from_the_plan() {
+        let mut plan = migrated_plan();
+        plan.unit_price = 1_000;
+        plan.included_units = 10;
+        let subscription = migrated_subscription(&mut plan, 0);
+        assert_eq!(subscription.payer_authority, subscription.owner);
+        assert_eq!(subscription.quantity, 1);
+        assert_eq!(subscription.next_quantity, 1);
+        assert_eq!(subscription.term_in_seconds, plan.term_in_seconds);
+        assert_eq!(subscription.term_price, plan.price);
+        assert_eq!(subscription.next_term_date, 1_700_000_000);
+        assert_eq!(subscription.state, SubscriptionState::Active);
+        assert_eq!(subscription.unit_price, 1_000);
+        assert_eq!(subscription.included_units, 10);
+    }
+
+    #[test]
+    fn usage_is_billed_at_the_pricing_the_term_started_on() {
+        let mut plan = migrated_plan();
+        plan.unit_price = 1_000;
+        plan.included_units = 10;
+        let mut subscription = migrated_subscription(&mut plan, 0);
+        subscription.layout_version = Subscription::LAYOUT_VERSION;
+        subscription.usage_units = 15;
+        plan.unit_price = 5_000;
+        plan.included_units = 0;
+        assert_eq!(subscription.overage(&plan).unwrap(), 5_000);
+        // accounts that haven't kept it yet are billed at the plan's until they're migrated;
+        subscription.layout_version = 1;
+        assert_eq!(subscription.overage(&plan).unwrap(), 75_000);
+        upgrade(&mut subscription, &mut plan, 1).unwrap();
+        subscription.layout_version = Subscription::LAYOUT_VERSION;
+        assert_eq!(subscription.overage(&plan).unwrap(), 75_000);
+    }
+
+    #[test]
+    fn unversioned_subscriptions_are_counted_again() {
+        let mut plan = migrated_plan();
+        migrated_subscription(&mut plan, 0);
+        // past due subscriptions still have their term escrowed and go back to active;
+        let past_due = migrated_subscription(&mut plan, 2);
+        assert_eq!(past_due.state, SubscriptionState::Active);
+        let cancelling = migrated_subscription(&mut plan, 1);
+        assert_eq!(cancelling.state, SubscriptionState::PendingCancellation);
+        assert_eq!(plan.active_subscriptions, 2);
+        assert_eq!(plan.pending_cancellation_subscriptions, 1);
+        assert_eq!(plan.past_due_subscriptions, 0);
+        assert_eq!(plan.lifetime_subscriptions, 3);
+        assert_eq!(plan.unmigrated_subscriptions, 4);
+        assert_eq!(plan.live_subscriptions(), 7);
+    }
+}
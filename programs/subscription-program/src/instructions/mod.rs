@@ -1,6 +1,25 @@
+pub mod apply_coupon;
 pub mod cancel_subscription;
+pub mod change_plan;
 pub mod charge_subscription;
+pub mod charge_subscriptions_batch;
+pub mod close_plan;
 pub mod close_subscription;
+pub mod create_coupon;
 pub mod create_plan;
 pub mod create_subscription;
+pub mod gift_subscription;
+pub mod initialize_protocol_config;
+pub mod merchant_cancel_subscription;
+pub mod migrate_plan;
+pub mod migrate_subscription;
+pub mod pause_subscription;
+pub mod refresh_delegation;
+pub mod release_unmigrated_subscriptions;
+pub mod report_usage;
+pub mod resume_subscription;
 pub mod uncancel_subscription;
+pub mod update_plan;
+pub mod update_plan_state;
+pub mod update_protocol_config;
+pub mod update_quantity;
@@ -0,0 +1,62 @@
+use anchor_lang::prelude::*;
+
+use crate::{events::SubscriptionPaused, SubscriptionErrors};
+
+use super::{
+    create_plan::Plan,
+    create_subscription::{Subscription, SubscriptionState},
+};
+
+// pauses are counted over a rolling year starting at the first one in it;
+pub const PAUSE_WINDOW_SECONDS: i64 = 365 * 24 * 60 * 60;
+
+pub fn handle_pause_subscription(ctx: Context<PauseSubscriptionParams>) -> Result<()> {
+    // stops the term where it is, whatever is escrowed for it stays there until it's resumed;
+    let subscription_account = &mut ctx.accounts.subscription_account;
+    let plan_account = &mut ctx.accounts.plan_account;
+    let current = Clock::get()?.unix_timestamp;
+    if plan_account.max_pauses_per_year == 0 {
+        return Err(SubscriptionErrors::PausingDisabled.into());
+    }
+    if current >= subscription_account.pause_window_start + PAUSE_WINDOW_SECONDS {
+        subscription_account.pause_window_start = current;
+        subscription_account.pauses_in_window = 0;
+    }
+    if subscription_account.pauses_in_window >= plan_account.max_pauses_per_year {
+        return Err(SubscriptionErrors::PauseLimitReached.into());
+    }
+    subscription_account.pauses_in_window += 1;
+    subscription_account.paused_at = current;
+    subscription_account.transition(plan_account, SubscriptionState::Paused)?;
+    emit!(SubscriptionPaused {
+        plan: plan_account.key(),
+        subscription: subscription_account.key(),
+        subscriber: subscription_account.owner,
+        term_index: subscription_account.term_index,
+        pauses_in_window: subscription_account.pauses_in_window,
+        max_pause_seconds: plan_account.max_pause_seconds,
+        timestamp: current,
+    });
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct PauseSubscriptionParams<'info> {
+    #[account(
+        mut,
+        seeds = [b"subscription".as_ref(), subscription_account.owner.key().as_ref(), plan_account.key().as_ref()],
+        constraint = plan_account.cancel_authority.allows(&subscription_account, payer.key()) @ SubscriptionErrors::Unauthorized,
+        constraint = subscription_account.state == SubscriptionState::Active @ SubscriptionErrors::InvalidSubscriptionState,
+        bump,
+    )]
+    pub subscription_account: Account<'info, Subscription>,
+    #[account(
+        mut,
+        seeds = [b"plan".as_ref(), plan_account.owner.key().as_ref(), plan_account.code_seed()],
+        bump,
+    )]
+    pub plan_account: Account<'info, Plan>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
@@ -0,0 +1,73 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{approve, Approve, TokenAccount, TokenInterface};
+
+use crate::{events::DelegationRefreshed, SubscriptionErrors};
+
+use super::{
+    create_plan::Plan,
+    create_subscription::{Subscription, SubscriptionState},
+};
+
+pub fn handle_refresh_delegation(
+    ctx: Context<RefreshDelegationParams>,
+    data: RefreshDelegationData,
+) -> Result<()> {
+    // approves a new allowance in place of whatever is left of the old one; a subscription whose
+    // allowance ran out is charged again on the next crank;
+    let subscription_account = &mut ctx.accounts.subscription_account;
+    let payer_token_account = &ctx.accounts.payer_token_account;
+    let payer = &ctx.accounts.payer;
+    let token_program = &ctx.accounts.token_program;
+    let approve_accounts = Approve {
+        delegate: subscription_account.to_account_info(),
+        to: payer_token_account.to_account_info(),
+        authority: payer.to_account_info(),
+    };
+    approve(
+        CpiContext::new(token_program.to_account_info(), approve_accounts),
+        data.delegation_amount,
+    )?;
+    subscription_account.delegated_allowance = data.delegation_amount;
+    emit!(DelegationRefreshed {
+        plan: ctx.accounts.plan_account.key(),
+        subscription: subscription_account.key(),
+        subscriber: subscription_account.owner,
+        payer_authority: payer.key(),
+        delegation_amount: data.delegation_amount,
+        state: subscription_account.state.clone(),
+        timestamp: Clock::get()?.unix_timestamp,
+    });
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct RefreshDelegationParams<'info> {
+    #[account(
+        mut,
+        seeds = [b"subscription".as_ref(), subscription_account.owner.key().as_ref(), plan_account.key().as_ref()],
+        constraint = subscription_account.payer_authority == payer.key() @ SubscriptionErrors::Unauthorized,
+        constraint = !subscription_account.gifted @ SubscriptionErrors::GiftedSubscription,
+        constraint = subscription_account.state != SubscriptionState::Lapsed @ SubscriptionErrors::InvalidSubscriptionState,
+        constraint = subscription_account.state != SubscriptionState::Cancelled @ SubscriptionErrors::InvalidSubscriptionState,
+        bump,
+    )]
+    pub subscription_account: Account<'info, Subscription>,
+    #[account(
+        seeds = [b"plan".as_ref(), plan_account.owner.key().as_ref(), plan_account.code_seed()],
+        bump,
+    )]
+    pub plan_account: Account<'info, Plan>,
+    #[account(
+        mut,
+        address = subscription_account.payer_token_account @ SubscriptionErrors::TokenAccountMismatch,
+        constraint = payer_token_account.owner == payer.key() @ SubscriptionErrors::InvalidTokenAccountOwner,
+    )]
+    pub payer_token_account: InterfaceAccount<'info, TokenAccount>,
+    pub payer: Signer<'info>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Debug)]
+pub struct RefreshDelegationData {
+    pub delegation_amount: u64,
+}
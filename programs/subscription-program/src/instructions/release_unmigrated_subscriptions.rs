@@ -0,0 +1,40 @@
+use anchor_lang::prelude::*;
+
+use crate::{events::UnmigratedSubscriptionsReleased, SubscriptionErrors};
+
+use super::create_plan::Plan;
+
+pub fn handle_release_unmigrated_subscriptions(
+    ctx: Context<ReleaseUnmigratedSubscriptionsParams>,
+) -> Result<()> {
+    // the count carried over from the original program includes subscriptions that were closed
+    // before the migration and can never be migrated; once the last term any of them could have
+    // paid for is over, nothing in escrow is owed back to them and the owner can stop waiting;
+    let plan_account = &mut ctx.accounts.plan_account;
+    let current = Clock::get()?.unix_timestamp;
+    if current < plan_account.unmigrated_terms_end {
+        return Err(SubscriptionErrors::UnmigratedTermsNotOver.into());
+    }
+    let released = plan_account.unmigrated_subscriptions;
+    plan_account.unmigrated_subscriptions = 0;
+    emit!(UnmigratedSubscriptionsReleased {
+        plan: plan_account.key(),
+        released,
+        timestamp: current,
+    });
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct ReleaseUnmigratedSubscriptionsParams<'info> {
+    #[account(
+        mut,
+        seeds = [b"plan".as_ref(), plan_account.owner.key().as_ref(), plan_account.code_seed()],
+        constraint = plan_account.owner == payer.key() @ SubscriptionErrors::Unauthorized,
+        bump,
+    )]
+    pub plan_account: Account<'info, Plan>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
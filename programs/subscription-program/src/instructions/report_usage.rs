@@ -0,0 +1,49 @@
+use anchor_lang::prelude::*;
+
+use crate::{events::UsageReported, SubscriptionErrors};
+
+use super::{
+    create_plan::Plan,
+    create_subscription::{Subscription, SubscriptionState},
+};
+
+pub fn handle_report_usage(ctx: Context<ReportUsageParams>, data: ReportUsageData) -> Result<()> {
+    // usage adds up over the term and is billed by the charge that ends it;
+    let subscription_account = &mut ctx.accounts.subscription_account;
+    subscription_account.usage_units = subscription_account
+        .usage_units
+        .checked_add(data.units)
+        .ok_or(SubscriptionErrors::MathOverflow)?;
+    emit!(UsageReported {
+        plan: ctx.accounts.plan_account.key(),
+        subscription: subscription_account.key(),
+        units: data.units,
+        usage_units: subscription_account.usage_units,
+        timestamp: Clock::get()?.unix_timestamp,
+    });
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct ReportUsageParams<'info> {
+    #[account(
+        mut,
+        seeds = [b"subscription".as_ref(), subscription_account.owner.key().as_ref(), plan_account.key().as_ref()],
+        constraint = subscription_account.state != SubscriptionState::Lapsed @ SubscriptionErrors::InvalidSubscriptionState,
+        constraint = subscription_account.state != SubscriptionState::Cancelled @ SubscriptionErrors::InvalidSubscriptionState,
+        bump,
+    )]
+    pub subscription_account: Account<'info, Subscription>,
+    #[account(
+        seeds = [b"plan".as_ref(), plan_account.owner.key().as_ref(), plan_account.code_seed()],
+        constraint = plan_account.usage_authority == usage_authority.key() @ SubscriptionErrors::Unauthorized,
+        bump,
+    )]
+    pub plan_account: Account<'info, Plan>,
+    pub usage_authority: Signer<'info>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Debug)]
+pub struct ReportUsageData {
+    pub units: u64,
+}
@@ -0,0 +1,60 @@
+use anchor_lang::prelude::*;
+
+use crate::{events::SubscriptionResumed, SubscriptionErrors};
+
+use super::{
+    create_plan::{Plan, PlanState},
+    create_subscription::{Subscription, SubscriptionState},
+};
+
+pub fn handle_resume_subscription(ctx: Context<ResumeSubscriptionParams>) -> Result<()> {
+    // whoever can cancel can resume at any time, once the longest pause the plan allows is over
+    // anyone can, so a subscription can't stay paused past it;
+    let subscription_account = &mut ctx.accounts.subscription_account;
+    let plan_account = &mut ctx.accounts.plan_account;
+    let payer = &ctx.accounts.payer;
+    let current = Clock::get()?.unix_timestamp;
+    let paused_at = subscription_account.paused_at;
+    // a sunset plan isn't coming back, so anyone can resume its paused subscriptions to run out
+    // what's left of their term and end;
+    let pause_over = plan_account.state == PlanState::Sunset
+        || matches!(subscription_account.pause_ends(plan_account)?, Some(ends) if current >= ends);
+    if !pause_over
+        && !plan_account
+            .cancel_authority
+            .allows(subscription_account, payer.key())
+    {
+        return Err(SubscriptionErrors::PauseNotOver.into());
+    }
+    subscription_account.resume(plan_account, current)?;
+    emit!(SubscriptionResumed {
+        plan: plan_account.key(),
+        subscription: subscription_account.key(),
+        subscriber: subscription_account.owner,
+        term_index: subscription_account.term_index,
+        paused_seconds: current - paused_at,
+        next_term_date: subscription_account.next_term_date,
+        timestamp: current,
+    });
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct ResumeSubscriptionParams<'info> {
+    #[account(
+        mut,
+        seeds = [b"subscription".as_ref(), subscription_account.owner.key().as_ref(), plan_account.key().as_ref()],
+        constraint = subscription_account.state == SubscriptionState::Paused @ SubscriptionErrors::InvalidSubscriptionState,
+        bump,
+    )]
+    pub subscription_account: Account<'info, Subscription>,
+    #[account(
+        mut,
+        seeds = [b"plan".as_ref(), plan_account.owner.key().as_ref(), plan_account.code_seed()],
+        bump,
+    )]
+    pub plan_account: Account<'info, Plan>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
@@ -0,0 +1,111 @@
+use anchor_lang::prelude::*;
+
+use crate::{events::PlanUpdated, math::BPS_DENOMINATOR, SubscriptionErrors};
+
+use super::create_plan::{CancelAuthority, KeeperReward, Plan};
+
+pub fn handle_update_plan(ctx: Context<UpdatePlanParams>, data: UpdatePlanData) -> Result<()> {
+    // price and term changes only apply to terms that start after this; subscribers keep the price
+    // and length they were charged for until their next_term_date;
+    let plan_account = &mut ctx.accounts.plan_account;
+    Plan::validate_terms(
+        data.price.unwrap_or(plan_account.price),
+        data.unit_price.unwrap_or(plan_account.unit_price),
+        data.term_in_seconds.unwrap_or(plan_account.term_in_seconds),
+    )?;
+    if let Some(price) = data.price {
+        plan_account.price = price;
+    }
+    if let Some(term_in_seconds) = data.term_in_seconds {
+        plan_account.term_in_seconds = term_in_seconds;
+    }
+    if let Some(retry_limit) = data.retry_limit {
+        plan_account.retry_limit = retry_limit;
+    }
+    if let Some(retry_interval_seconds) = data.retry_interval_seconds {
+        plan_account.retry_interval_seconds = retry_interval_seconds;
+    }
+    if let Some(grace_seconds) = data.grace_seconds {
+        plan_account.grace_seconds = grace_seconds;
+    }
+    if let Some(keeper_reward) = data.keeper_reward {
+        plan_account.keeper_reward = keeper_reward;
+    }
+    if let Some(usage_authority) = data.usage_authority {
+        plan_account.usage_authority = usage_authority;
+    }
+    // usage pricing is kept on the subscription when a term starts, like the price;
+    if let Some(unit_price) = data.unit_price {
+        plan_account.unit_price = unit_price;
+    }
+    if let Some(included_units) = data.included_units {
+        plan_account.included_units = included_units;
+    }
+    if let Some(cancel_authority) = data.cancel_authority {
+        plan_account.cancel_authority = cancel_authority;
+    }
+    if let Some(prepay_discount_bps) = data.prepay_discount_bps {
+        if prepay_discount_bps as u128 > BPS_DENOMINATOR {
+            return Err(SubscriptionErrors::InvalidPrepayDiscount.into());
+        }
+        plan_account.prepay_discount_bps = prepay_discount_bps;
+    }
+    // checked when a subscription pauses or resumes, so these apply to pauses in progress too;
+    if let Some(max_pause_seconds) = data.max_pause_seconds {
+        plan_account.max_pause_seconds = max_pause_seconds;
+    }
+    if let Some(max_pauses_per_year) = data.max_pauses_per_year {
+        plan_account.max_pauses_per_year = max_pauses_per_year;
+    }
+    if let Some(name) = data.name {
+        Plan::validate_metadata(&name, &plan_account.metadata_uri)?;
+        plan_account.name = name;
+    }
+    if let Some(metadata_uri) = data.metadata_uri {
+        Plan::validate_metadata(&plan_account.name, &metadata_uri)?;
+        plan_account.metadata_uri = metadata_uri;
+    }
+    plan_account.validate_durations()?;
+    plan_account.version += 1;
+    emit!(PlanUpdated {
+        plan: plan_account.key(),
+        version: plan_account.version,
+        price: plan_account.price,
+        term_in_seconds: plan_account.term_in_seconds,
+        timestamp: Clock::get()?.unix_timestamp,
+    });
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct UpdatePlanParams<'info> {
+    #[account(
+        mut,
+        seeds = [b"plan".as_ref(), plan_account.owner.key().as_ref(), plan_account.code_seed()],
+        constraint = plan_account.owner == payer.key() @ SubscriptionErrors::Unauthorized,
+        bump,
+    )]
+    pub plan_account: Account<'info, Plan>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Debug)]
+pub struct UpdatePlanData {
+    pub price: Option<u64>,
+    pub term_in_seconds: Option<u64>,
+    pub retry_limit: Option<u8>,
+    pub retry_interval_seconds: Option<u64>,
+    pub grace_seconds: Option<u64>,
+    pub keeper_reward: Option<KeeperReward>,
+    pub usage_authority: Option<Pubkey>,
+    pub unit_price: Option<u64>,
+    pub included_units: Option<u64>,
+    pub cancel_authority: Option<CancelAuthority>,
+    pub prepay_discount_bps: Option<u16>,
+    pub max_pause_seconds: Option<u64>,
+    pub max_pauses_per_year: Option<u8>,
+    pub name: Option<String>,
+    pub metadata_uri: Option<String>,
+}
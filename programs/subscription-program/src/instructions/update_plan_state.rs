@@ -0,0 +1,49 @@
+use anchor_lang::prelude::*;
+
+use crate::{events::PlanStateChanged, SubscriptionErrors};
+
+use super::create_plan::{Plan, PlanState};
+
+pub fn handle_update_plan_state(
+    ctx: Context<UpdatePlanStateParams>,
+    data: UpdatePlanStateData,
+) -> Result<()> {
+    let plan_account = &mut ctx.accounts.plan_account;
+    // a sunset plan can only be closed, and closing goes through close_plan;
+    let allowed = matches!(
+        (&plan_account.state, &data.state),
+        (PlanState::Active, PlanState::ClosedToNewSubscribers)
+            | (PlanState::Active, PlanState::Sunset)
+            | (PlanState::ClosedToNewSubscribers, PlanState::Active)
+            | (PlanState::ClosedToNewSubscribers, PlanState::Sunset)
+    );
+    if !allowed {
+        return Err(SubscriptionErrors::InvalidPlanStateTransition.into());
+    }
+    plan_account.state = data.state;
+    emit!(PlanStateChanged {
+        plan: plan_account.key(),
+        state: plan_account.state.clone(),
+        timestamp: Clock::get()?.unix_timestamp,
+    });
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct UpdatePlanStateParams<'info> {
+    #[account(
+        mut,
+        seeds = [b"plan".as_ref(), plan_account.owner.key().as_ref(), plan_account.code_seed()],
+        constraint = plan_account.owner == payer.key() @ SubscriptionErrors::Unauthorized,
+        bump,
+    )]
+    pub plan_account: Account<'info, Plan>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Debug)]
+pub struct UpdatePlanStateData {
+    pub state: PlanState,
+}
@@ -0,0 +1,50 @@
+use anchor_lang::prelude::*;
+
+use crate::{math::RoundingPolicy, SubscriptionErrors};
+
+use super::initialize_protocol_config::{ProtocolConfig, MAX_FEE_BPS};
+
+pub fn handle_update_protocol_config(
+    ctx: Context<UpdateProtocolConfigParams>,
+    data: UpdateProtocolConfigData,
+) -> Result<()> {
+    let protocol_config = &mut ctx.accounts.protocol_config;
+    if let Some(fee_bps) = data.fee_bps {
+        if fee_bps > MAX_FEE_BPS {
+            return Err(SubscriptionErrors::InvalidFee.into());
+        }
+        protocol_config.fee_bps = fee_bps;
+    }
+    if let Some(fee_recipient) = data.fee_recipient {
+        protocol_config.fee_recipient = fee_recipient;
+    }
+    if let Some(rounding) = data.rounding {
+        protocol_config.rounding = rounding;
+    }
+    if let Some(authority) = data.authority {
+        protocol_config.authority = authority;
+    }
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct UpdateProtocolConfigParams<'info> {
+    #[account(
+        mut,
+        seeds = [b"protocol_config".as_ref()],
+        constraint = protocol_config.authority == payer.key() @ SubscriptionErrors::Unauthorized,
+        bump,
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Debug)]
+pub struct UpdateProtocolConfigData {
+    pub authority: Option<Pubkey>,
+    pub fee_bps: Option<u16>,
+    pub fee_recipient: Option<Pubkey>,
+    pub rounding: Option<RoundingPolicy>,
+}
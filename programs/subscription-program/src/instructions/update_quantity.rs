@@ -0,0 +1,138 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{
+    transfer_checked, Mint, TokenAccount, TokenInterface, TransferChecked,
+};
+
+use crate::{
+    events::QuantityUpdated,
+    math::{prorate, scale},
+    SubscriptionErrors,
+};
+
+use super::{
+    create_plan::Plan,
+    create_subscription::{Subscription, SubscriptionState},
+    initialize_protocol_config::ProtocolConfig,
+};
+
+pub fn handle_update_quantity(
+    ctx: Context<UpdateQuantityParams>,
+    data: UpdateQuantityData,
+) -> Result<()> {
+    // only whoever pays can change the seat count; added seats are paid for now for what's left
+    // of the term, removed seats stay until the term ends since they've already been paid for;
+    let subscription_account = &mut ctx.accounts.subscription_account;
+    let plan_token_account = &mut ctx.accounts.plan_token_account;
+    let payer_token_account = &ctx.accounts.payer_token_account;
+    let protocol_config = &ctx.accounts.protocol_config;
+    let mint_account = &ctx.accounts.mint_account;
+    let payer = &ctx.accounts.payer;
+    let token_program = &ctx.accounts.token_program;
+    let current = Clock::get()?.unix_timestamp;
+    if data.quantity == 0 {
+        return Err(SubscriptionErrors::InvalidQuantity.into());
+    }
+
+    let mut charged = 0;
+    if data.quantity > subscription_account.quantity {
+        let added = (data.quantity - subscription_account.quantity) as u64;
+        let rounding = protocol_config.rounding.for_charge();
+        let added_term_price = scale(
+            subscription_account.term_price,
+            added,
+            subscription_account.quantity as u64,
+            rounding,
+        )?;
+        let remaining = (subscription_account.next_term_date
+            - subscription_account.term_clock(current))
+        .max(0) as u64;
+        charged = prorate(
+            added_term_price,
+            remaining,
+            subscription_account.term_in_seconds,
+            rounding,
+        )?;
+        if charged > 0 {
+            let transfer_accounts = TransferChecked {
+                from: payer_token_account.to_account_info().clone(),
+                mint: mint_account.to_account_info().clone(),
+                to: plan_token_account.to_account_info().clone(),
+                authority: payer.to_account_info().clone(),
+            };
+            let before = plan_token_account.amount;
+            transfer_checked(
+                CpiContext::new(token_program.to_account_info().clone(), transfer_accounts),
+                charged,
+                mint_account.decimals,
+            )?;
+            plan_token_account.reload()?;
+            let received = plan_token_account
+                .amount
+                .checked_sub(before)
+                .ok_or(SubscriptionErrors::MathOverflow)?;
+            subscription_account.term_price = subscription_account
+                .term_price
+                .checked_add(received)
+                .ok_or(SubscriptionErrors::MathOverflow)?;
+        }
+        subscription_account.quantity = data.quantity;
+    }
+    subscription_account.next_quantity = data.quantity;
+    emit!(QuantityUpdated {
+        plan: ctx.accounts.plan_account.key(),
+        subscription: subscription_account.key(),
+        subscriber: subscription_account.owner,
+        quantity: subscription_account.quantity,
+        next_quantity: subscription_account.next_quantity,
+        charged,
+        timestamp: current,
+    });
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct UpdateQuantityParams<'info> {
+    #[account(
+        mut,
+        seeds = [b"subscription".as_ref(), subscription_account.owner.key().as_ref(), plan_account.key().as_ref()],
+        constraint = subscription_account.payer_authority == payer.key() @ SubscriptionErrors::Unauthorized,
+        constraint = !subscription_account.gifted @ SubscriptionErrors::GiftedSubscription,
+        constraint = subscription_account.state != SubscriptionState::Lapsed @ SubscriptionErrors::InvalidSubscriptionState,
+        constraint = subscription_account.state != SubscriptionState::Cancelled @ SubscriptionErrors::InvalidSubscriptionState,
+        bump,
+    )]
+    pub subscription_account: Account<'info, Subscription>,
+    #[account(
+        seeds = [b"plan".as_ref(), plan_account.owner.key().as_ref(), plan_account.code_seed()],
+        bump,
+    )]
+    pub plan_account: Account<'info, Plan>,
+    #[account(
+        mut,
+        constraint = plan_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = plan_token_account.owner == plan_account.key() @ SubscriptionErrors::InvalidTokenAccountOwner,
+    )]
+    pub plan_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = payer_token_account.mint == plan_account.token_mint @ SubscriptionErrors::MintMismatch,
+        constraint = payer_token_account.owner == payer.key() @ SubscriptionErrors::InvalidTokenAccountOwner,
+    )]
+    pub payer_token_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        seeds = [b"protocol_config".as_ref()],
+        bump,
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+    #[account(address = plan_account.token_mint @ SubscriptionErrors::MintMismatch)]
+    pub mint_account: InterfaceAccount<'info, Mint>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Debug)]
+pub struct UpdateQuantityData {
+    pub quantity: u32,
+}
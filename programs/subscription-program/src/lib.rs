@@ -1,10 +1,17 @@
 use anchor_lang::prelude::*;
 pub mod errors;
 pub use errors::SubscriptionErrors;
+pub mod events;
 pub mod instructions;
+pub mod math;
 use instructions::{
-    cancel_subscription::*, charge_subscription::*, close_subscription::*, create_plan::*,
-    create_subscription::*, uncancel_subscription::*,
+    apply_coupon::*, cancel_subscription::*, change_plan::*, charge_subscription::*,
+    charge_subscriptions_batch::*, close_plan::*, close_subscription::*, create_coupon::*,
+    create_plan::*, create_subscription::*, gift_subscription::*, initialize_protocol_config::*,
+    merchant_cancel_subscription::*, migrate_plan::*, migrate_subscription::*,
+    pause_subscription::*, refresh_delegation::*, release_unmigrated_subscriptions::*,
+    report_usage::*, resume_subscription::*, uncancel_subscription::*, update_plan::*,
+    update_plan_state::*, update_protocol_config::*, update_quantity::*,
 };
 
 declare_id!("6qMvvisbUX3Co1sZa7DkyCXF8FcsTjzKSQHcaDoqSLbw");
@@ -16,10 +23,43 @@ pub mod subscription_program {
 
     use super::*;
 
+    pub fn initialize_protocol_config(
+        ctx: Context<InitializeProtocolConfigParams>,
+        data: InitializeProtocolConfigData,
+    ) -> Result<()> {
+        handle_initialize_protocol_config(ctx, data)
+    }
+
+    pub fn update_protocol_config(
+        ctx: Context<UpdateProtocolConfigParams>,
+        data: UpdateProtocolConfigData,
+    ) -> Result<()> {
+        handle_update_protocol_config(ctx, data)
+    }
+
     pub fn create_plan(ctx: Context<CreatePlanParams>, data: CreatePlanData) -> Result<()> {
         handle_create_plan(ctx, data)
     }
 
+    pub fn update_plan(ctx: Context<UpdatePlanParams>, data: UpdatePlanData) -> Result<()> {
+        handle_update_plan(ctx, data)
+    }
+
+    pub fn update_plan_state(
+        ctx: Context<UpdatePlanStateParams>,
+        data: UpdatePlanStateData,
+    ) -> Result<()> {
+        handle_update_plan_state(ctx, data)
+    }
+
+    pub fn close_plan(ctx: Context<ClosePlanParams>) -> Result<()> {
+        handle_close_plan(ctx)
+    }
+
+    pub fn create_coupon(ctx: Context<CreateCouponParams>, data: CreateCouponData) -> Result<()> {
+        handle_create_coupon(ctx, data)
+    }
+
     pub fn create_subscription(
         ctx: Context<CreateSubscriptionParams>,
         data: CreateSubscriptionData,
@@ -27,10 +67,45 @@ pub mod subscription_program {
         handle_create_subscription(ctx, data)
     }
 
+    pub fn gift_subscription(
+        ctx: Context<GiftSubscriptionParams>,
+        data: GiftSubscriptionData,
+    ) -> Result<()> {
+        handle_gift_subscription(ctx, data)
+    }
+
     pub fn charge_subscription(ctx: Context<ChargeSubscriptionParams>) -> Result<()> {
         handle_charge_subscription(ctx)
     }
 
+    pub fn charge_subscriptions_batch<'info>(
+        ctx: Context<'_, '_, '_, 'info, ChargeSubscriptionsBatchParams<'info>>,
+    ) -> Result<()> {
+        handle_charge_subscriptions_batch(ctx)
+    }
+
+    pub fn apply_coupon(ctx: Context<ApplyCouponParams>) -> Result<()> {
+        handle_apply_coupon(ctx)
+    }
+
+    pub fn update_quantity(
+        ctx: Context<UpdateQuantityParams>,
+        data: UpdateQuantityData,
+    ) -> Result<()> {
+        handle_update_quantity(ctx, data)
+    }
+
+    pub fn report_usage(ctx: Context<ReportUsageParams>, data: ReportUsageData) -> Result<()> {
+        handle_report_usage(ctx, data)
+    }
+
+    pub fn refresh_delegation(
+        ctx: Context<RefreshDelegationParams>,
+        data: RefreshDelegationData,
+    ) -> Result<()> {
+        handle_refresh_delegation(ctx, data)
+    }
+
     pub fn cancel_subscription(ctx: Context<CancelSubscriptionParams>) -> Result<()> {
         handle_cancel_subscription(ctx)
     }
@@ -39,7 +114,40 @@ pub mod subscription_program {
         handle_uncancel_subscription(ctx)
     }
 
+    pub fn pause_subscription(ctx: Context<PauseSubscriptionParams>) -> Result<()> {
+        handle_pause_subscription(ctx)
+    }
+
+    pub fn resume_subscription(ctx: Context<ResumeSubscriptionParams>) -> Result<()> {
+        handle_resume_subscription(ctx)
+    }
+
+    pub fn merchant_cancel_subscription(
+        ctx: Context<MerchantCancelSubscriptionParams>,
+        data: MerchantCancelData,
+    ) -> Result<()> {
+        handle_merchant_cancel_subscription(ctx, data)
+    }
+
+    pub fn change_plan(ctx: Context<ChangePlanParams>, data: ChangePlanData) -> Result<()> {
+        handle_change_plan(ctx, data)
+    }
+
     pub fn close_subscription(ctx: Context<CloseSubscriptionParams>) -> Result<()> {
         handle_close_subscription(ctx)
     }
+
+    pub fn migrate_plan(ctx: Context<MigratePlanParams>) -> Result<()> {
+        handle_migrate_plan(ctx)
+    }
+
+    pub fn migrate_subscription(ctx: Context<MigrateSubscriptionParams>) -> Result<()> {
+        handle_migrate_subscription(ctx)
+    }
+
+    pub fn release_unmigrated_subscriptions(
+        ctx: Context<ReleaseUnmigratedSubscriptionsParams>,
+    ) -> Result<()> {
+        handle_release_unmigrated_subscriptions(ctx)
+    }
 }
@@ -0,0 +1,182 @@
+use anchor_lang::prelude::*;
+
+use crate::SubscriptionErrors;
+
+pub const BPS_DENOMINATOR: u128 = 10_000;
+
+// which side keeps the leftover base unit when a prorated amount doesn't divide evenly;
+// protocol fees always round down so the merchant is never charged more than fee_bps;
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, Debug, PartialEq)]
+pub enum RoundingPolicy {
+    // refunds round up;
+    #[default]
+    FavorSubscriber,
+    // refunds round down;
+    FavorMerchant,
+}
+
+impl RoundingPolicy {
+    // a prorated charge rounds the other way from a refund so the same side comes out ahead;
+    pub fn for_charge(self) -> Self {
+        match self {
+            RoundingPolicy::FavorSubscriber => RoundingPolicy::FavorMerchant,
+            RoundingPolicy::FavorMerchant => RoundingPolicy::FavorSubscriber,
+        }
+    }
+}
+
+// fee_bps of amount, rounded down;
+pub fn fee_amount(amount: u64, fee_bps: u16) -> Result<u64> {
+    mul_div(amount, fee_bps as u64, BPS_DENOMINATOR as u64, false)
+}
+
+// amount minus its fee, so the two parts always add back up to amount;
+pub fn split_fee(amount: u64, fee_bps: u16) -> Result<(u64, u64)> {
+    let fee = fee_amount(amount, fee_bps)?;
+    let net = amount
+        .checked_sub(fee)
+        .ok_or(SubscriptionErrors::MathOverflow)?;
+    Ok((net, fee))
+}
+
+// the share of amount covering `remaining` out of `total` seconds, never more than amount;
+pub fn prorate(amount: u64, remaining: u64, total: u64, rounding: RoundingPolicy) -> Result<u64> {
+    if total == 0 {
+        return Err(SubscriptionErrors::InvalidTerm.into());
+    }
+    scale(amount, remaining.min(total), total, rounding)
+}
+
+// amount * numerator / denominator, e.g. the price of added seats from what the current seats
+// paid; rounded up when rounding favors the subscriber, like a refund;
+pub fn scale(
+    amount: u64,
+    numerator: u64,
+    denominator: u64,
+    rounding: RoundingPolicy,
+) -> Result<u64> {
+    mul_div(
+        amount,
+        numerator,
+        denominator,
+        rounding == RoundingPolicy::FavorSubscriber,
+    )
+}
+
+// `timestamp` plus a number of seconds as they're stored on plans;
+pub fn add_seconds(timestamp: i64, seconds: u64) -> Result<i64> {
+    i64::try_from(seconds)
+        .ok()
+        .and_then(|seconds| timestamp.checked_add(seconds))
+        .ok_or(SubscriptionErrors::MathOverflow.into())
+}
+
+fn mul_div(amount: u64, numerator: u64, denominator: u64, round_up: bool) -> Result<u64> {
+    if denominator == 0 {
+        return Err(SubscriptionErrors::MathOverflow.into());
+    }
+    let product = (amount as u128)
+        .checked_mul(numerator as u128)
+        .ok_or(SubscriptionErrors::MathOverflow)?;
+    let denominator = denominator as u128;
+    let mut quotient = product / denominator;
+    if round_up && product % denominator != 0 {
+        quotient += 1;
+    }
+    u64::try_from(quotient).map_err(|_| SubscriptionErrors::MathOverflow.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fee_rounds_down() {
+        assert_eq!(fee_amount(10_000_000_000, 300).unwrap(), 300_000_000);
+        assert_eq!(fee_amount(33, 300).unwrap(), 0);
+        assert_eq!(fee_amount(34, 300).unwrap(), 1);
+        assert_eq!(fee_amount(0, 300).unwrap(), 0);
+    }
+
+    #[test]
+    fn fee_on_max_price() {
+        assert_eq!(fee_amount(u64::MAX, 10_000).unwrap(), u64::MAX);
+        assert_eq!(fee_amount(u64::MAX, 0).unwrap(), 0);
+        assert_eq!(
+            fee_amount(u64::MAX, 300).unwrap(),
+            ((u64::MAX as u128) * 300 / 10_000) as u64
+        );
+    }
+
+    #[test]
+    fn split_fee_adds_up() {
+        for amount in [0, 1, 9_999, 10_000_000_001, u64::MAX] {
+            let (net, fee) = split_fee(amount, 300).unwrap();
+            assert_eq!(net + fee, amount);
+        }
+    }
+
+    #[test]
+    fn prorate_follows_rounding_policy() {
+        assert_eq!(
+            prorate(10, 1, 3, RoundingPolicy::FavorSubscriber).unwrap(),
+            4
+        );
+        assert_eq!(prorate(10, 1, 3, RoundingPolicy::FavorMerchant).unwrap(), 3);
+        assert_eq!(
+            prorate(9, 1, 3, RoundingPolicy::FavorSubscriber).unwrap(),
+            3
+        );
+        assert_eq!(prorate(9, 1, 3, RoundingPolicy::FavorMerchant).unwrap(), 3);
+    }
+
+    #[test]
+    fn prorated_charge_rounds_the_other_way() {
+        let rounding = RoundingPolicy::FavorSubscriber.for_charge();
+        assert_eq!(prorate(10, 1, 3, rounding).unwrap(), 3);
+        let rounding = RoundingPolicy::FavorMerchant.for_charge();
+        assert_eq!(prorate(10, 1, 3, rounding).unwrap(), 4);
+    }
+
+    #[test]
+    fn prorate_on_max_price() {
+        let policy = RoundingPolicy::FavorMerchant;
+        assert_eq!(
+            prorate(u64::MAX, u64::MAX, u64::MAX, policy).unwrap(),
+            u64::MAX
+        );
+        assert_eq!(prorate(u64::MAX, 1, 2, policy).unwrap(), u64::MAX / 2);
+        assert_eq!(
+            prorate(u64::MAX, 1, 2, RoundingPolicy::FavorSubscriber).unwrap(),
+            u64::MAX / 2 + 1
+        );
+        assert_eq!(prorate(u64::MAX, 0, 30, policy).unwrap(), 0);
+    }
+
+    #[test]
+    fn prorate_never_exceeds_amount() {
+        let policy = RoundingPolicy::FavorSubscriber;
+        assert_eq!(prorate(100, 60, 30, policy).unwrap(), 100);
+    }
+
+    #[test]
+    fn prorate_rejects_zero_term() {
+        assert!(prorate(100, 0, 0, RoundingPolicy::FavorSubscriber).is_err());
+    }
+
+    #[test]
+    fn add_seconds_is_checked() {
+        assert_eq!(add_seconds(1_000, 30).unwrap(), 1_030);
+        assert!(add_seconds(1_000, u64::MAX).is_err());
+        assert!(add_seconds(i64::MAX - 10, 30).is_err());
+    }
+
+    #[test]
+    fn scale_follows_rounding_policy() {
+        let policy = RoundingPolicy::FavorSubscriber;
+        assert_eq!(scale(10, 1, 3, policy).unwrap(), 4);
+        assert_eq!(scale(10, 1, 3, policy.for_charge()).unwrap(), 3);
+        assert_eq!(scale(10, 5, 2, policy).unwrap(), 25);
+        assert!(scale(10, 1, 0, policy).is_err());
+    }
+}
@@ -4,12 +4,19 @@ import { SubscriptionProgram } from "../target/types/subscription_program";
 import { PublicKey, Keypair } from "@solana/web3.js";
 import {
   createMint,
+  createInitializeMintInstruction,
+  createInitializeTransferFeeConfigInstruction,
+  ExtensionType,
   getAssociatedTokenAddressSync,
+  getMintLen,
   getOrCreateAssociatedTokenAccount,
   mintTo,
+  TOKEN_2022_PROGRAM_ID,
+  TOKEN_PROGRAM_ID,
 } from "@solana/spl-token";
 import chai, { expect } from "chai";
 import chaiAsPromised from "chai-as-promised";
+import { createHash } from "crypto";
 import { readFileSync } from "fs";
 
 chai.use(chaiAsPromised);
@@ -25,18 +32,71 @@ const deployer = Keypair.fromSecretKey(
   Uint8Array.from(JSON.parse(readFileSync("./deployer.json", "utf-8")))
 );
 
+const [protocolConfig] = anchor.web3.PublicKey.findProgramAddressSync(
+  [Buffer.from(anchor.utils.bytes.utf8.encode("protocol_config"))],
+  program.programId
+);
+
+const initializeProtocolConfig = async () => {
+  const existing = await connection.getAccountInfo(protocolConfig);
+  if (existing) {
+    return;
+  }
+  const airdropTx = await connection.requestAirdrop(
+    deployer.publicKey,
+    2000000000
+  );
+  await connection.confirmTransaction(airdropTx);
+  const [programData] = anchor.web3.PublicKey.findProgramAddressSync(
+    [program.programId.toBuffer()],
+    new PublicKey("BPFLoaderUpgradeab1e11111111111111111111111")
+  );
+  await program.methods
+    .initializeProtocolConfig({
+      feeBps: 300,
+      feeRecipient: deployer.publicKey,
+      rounding: { favorSubscriber: {} },
+    })
+    .accounts({
+      payer: deployer.publicKey,
+      protocolConfig,
+      program: program.programId,
+      programData,
+    })
+    .signers([deployer])
+    .rpc();
+};
+
+// plan addresses are derived from a hash of the code so codes can be longer than a seed;
+const planCodeSeed = (code: string) =>
+  createHash("sha256").update(code).digest();
+
 interface PlanConfig {
+  code?: string;
+  name?: string;
   termInSeconds?: number;
+  trialSeconds?: number;
+  retryLimit?: number;
+  retryIntervalSeconds?: number;
+  graceSeconds?: number;
+  keeperReward?: object;
+  usageAuthority?: PublicKey;
+  unitPrice?: number;
+  includedUnits?: number;
+  cancelAuthority?: object;
+  prepayDiscountBps?: number;
+  maxPauseSeconds?: number;
+  maxPausesPerYear?: number;
 }
 
 const createPlan = async (config: Partial<PlanConfig> = {}) => {
   const owner = anchor.web3.Keypair.generate();
-  const code = "test";
+  const code = config.code || "test";
   const [plan_account] = anchor.web3.PublicKey.findProgramAddressSync(
     [
       Buffer.from(anchor.utils.bytes.utf8.encode("plan")),
       owner.publicKey.toBuffer(),
-      Buffer.from(anchor.utils.bytes.utf8.encode(code)),
+      planCodeSeed(code),
     ],
     program.programId
   );
@@ -62,8 +122,22 @@ const createPlan = async (config: Partial<PlanConfig> = {}) => {
   await program.methods
     .createPlan({
       code,
+      name: config.name || "",
+      metadataUri: "",
       price: new anchor.BN(10 * 10 ** decimals),
       termInSeconds: new anchor.BN(config.termInSeconds || 30),
+      trialSeconds: new anchor.BN(config.trialSeconds || 0),
+      retryLimit: config.retryLimit || 0,
+      retryIntervalSeconds: new anchor.BN(config.retryIntervalSeconds || 0),
+      graceSeconds: new anchor.BN(config.graceSeconds || 0),
+      keeperReward: config.keeperReward || { none: {} },
+      usageAuthority: config.usageAuthority || PublicKey.default,
+      unitPrice: new anchor.BN((config.unitPrice || 0) * 10 ** decimals),
+      includedUnits: new anchor.BN(config.includedUnits || 0),
+      cancelAuthority: config.cancelAuthority || { either: {} },
+      prepayDiscountBps: config.prepayDiscountBps || 0,
+      maxPauseSeconds: new anchor.BN(config.maxPauseSeconds || 0),
+      maxPausesPerYear: config.maxPausesPerYear || 0,
     })
     .accounts({
       payer: owner.publicKey,
@@ -82,6 +156,16 @@ const createPlan = async (config: Partial<PlanConfig> = {}) => {
   };
 };
 
+const couponRedemptionAddress = (coupon: PublicKey, subscriber: PublicKey) =>
+  anchor.web3.PublicKey.findProgramAddressSync(
+    [
+      Buffer.from(anchor.utils.bytes.utf8.encode("coupon_redemption")),
+      coupon.toBuffer(),
+      subscriber.toBuffer(),
+    ],
+    program.programId
+  )[0];
+
 interface CreateSubscriptionData {
   owner: Keypair;
   mint: PublicKey;
@@ -89,11 +173,19 @@ interface CreateSubscriptionData {
   planTokenAccount: PublicKey;
   usePlanOwner?: boolean;
   amount?: number;
+  payer?: Keypair;
+  tokenProgram?: PublicKey;
+  coupon?: PublicKey;
+  quantity?: number;
+  beneficiary?: Keypair;
+  prepaidTerms?: number;
 }
 
 const createSubscription = async (data: CreateSubscriptionData) => {
   const { mint, owner, planAccount, planTokenAccount } = data;
-  const payer = !!data.usePlanOwner ? owner : anchor.web3.Keypair.generate();
+  const payer =
+    data.payer ||
+    (!!data.usePlanOwner ? owner : anchor.web3.Keypair.generate());
   const airdropTx = await connection.requestAirdrop(
     payer.publicKey,
     2000000000
@@ -104,7 +196,10 @@ const createSubscription = async (data: CreateSubscriptionData) => {
     payer,
     mint,
     payer.publicKey,
-    true
+    true,
+    undefined,
+    undefined,
+    data.tokenProgram
   );
   await mintTo(
     connection,
@@ -112,19 +207,42 @@ const createSubscription = async (data: CreateSubscriptionData) => {
     mint,
     payerTokenAccount.address,
     owner,
-    (data.amount || 100) * 10 ** 9
+    (data.amount || 100) * 10 ** 9,
+    [],
+    undefined,
+    data.tokenProgram
   );
+  // a sponsored beneficiary signs too, so no one can be subscribed without agreeing to it;
+  const beneficiary = data.beneficiary
+    ? data.beneficiary.publicKey
+    : payer.publicKey;
   const [subscriptionAccount] = anchor.web3.PublicKey.findProgramAddressSync(
     [
       Buffer.from(anchor.utils.bytes.utf8.encode("subscription")),
-      payer.publicKey.toBuffer(),
+      beneficiary.toBuffer(),
+      planAccount.toBuffer(),
+    ],
+    program.programId
+  );
+  const [trialAccount] = anchor.web3.PublicKey.findProgramAddressSync(
+    [
+      Buffer.from(anchor.utils.bytes.utf8.encode("trial")),
+      beneficiary.toBuffer(),
       planAccount.toBuffer(),
     ],
     program.programId
   );
+  const couponRedemption = data.coupon
+    ? couponRedemptionAddress(data.coupon, beneficiary)
+    : null;
+  // the trial record is only created for plans that have a trial;
+  const planData = await program.account.plan.fetch(planAccount);
+  const hasTrial = planData.trialSeconds.toNumber() > 0;
   await program.methods
     .createSubscription({
       delegationAmount: new anchor.BN(100000 * 10 ** 9),
+      quantity: data.quantity || 1,
+      prepaidTerms: data.prepaidTerms || 0,
     })
     .accounts({
       payer: payer.publicKey,
@@ -132,18 +250,53 @@ const createSubscription = async (data: CreateSubscriptionData) => {
       planAccount: planAccount,
       subscriptionAccount,
       planTokenAccount: planTokenAccount,
+      trialAccount: hasTrial ? trialAccount : null,
+      couponAccount: data.coupon || null,
+      couponRedemption,
+      beneficiary,
+      mintAccount: mint,
+      tokenProgram: data.tokenProgram || TOKEN_PROGRAM_ID,
     })
-    .signers([payer])
+    .signers(data.beneficiary ? [payer, data.beneficiary] : [payer])
     .rpc();
 
   return {
     subscriptionAccount,
     payer,
     payerTokenAccount,
+    trialAccount,
   };
 };
 
 describe("subscription-program", () => {
+  before(initializeProtocolConfig);
+
+  it("Only the protocol authority can update the config", async () => {
+    const random = anchor.web3.Keypair.generate();
+    const airdropTx = await connection.requestAirdrop(
+      random.publicKey,
+      2000000000
+    );
+    await connection.confirmTransaction(airdropTx);
+    await expect(
+      program.methods
+        .updateProtocolConfig({
+          authority: null,
+          feeBps: 0,
+          feeRecipient: null,
+          rounding: null,
+        })
+        .accounts({
+          payer: random.publicKey,
+          protocolConfig,
+        })
+        .signers([random])
+        .rpc()
+    ).to.eventually.rejected;
+    const data = await program.account.protocolConfig.fetch(protocolConfig);
+    expect(data.feeBps).to.eq(300);
+  });
+
   it("Creates Plan", async () => {
     // Add your test here.
     const { plan_account, mint, owner, planTokenAccount } = await createPlan();
@@ -151,9 +304,23 @@ describe("subscription-program", () => {
     console.log(data);
   });
 
+  it("Accepts long plan codes and rejects invalid ones", async () => {
+    const code = "enterprise-annual-billing-with-priority-support-2024";
+    const { plan_account } = await createPlan({
+      code,
+      name: "Enterprise (annual)",
+    });
+    const data = await program.account.plan.fetch(plan_account);
+    expect(data.code).to.eq(code);
+    expect(data.name).to.eq("Enterprise (annual)");
+    expect(Buffer.from(data.codeHash).equals(planCodeSeed(code))).to.eq(true);
+    await expect(createPlan({ code: "has spaces" })).to.eventually.rejected;
+    await expect(createPlan({ code: "x".repeat(65) })).to.eventually.rejected;
+  });
+
   it("Creates a subscription", async () => {
     const { plan_account, mint, owner, planTokenAccount } = await createPlan();
-    const { subscriptionAccount } = await createSubscription({
+    const { subscriptionAccount, trialAccount } = await createSubscription({
       owner,
       mint,
       planAccount: plan_account,
@@ -161,76 +328,144 @@ describe("subscription-program", () => {
     });
     const data = await program.account.subscription.fetch(subscriptionAccount);
     const planData = await program.account.plan.fetch(plan_account);
-    expect(planData.activeSubscriptions).to.eq(1);
+    expect(planData.activeSubscriptions.toNumber()).to.eq(1);
+    // the plan has no trial, so no trial record is paid for;
+    expect(await connection.getAccountInfo(trialAccount)).to.eq(null);
   });
 
-  it("Fails to charge before appropriate time", async () => {
+  it("Updates plan price without changing the current term", async () => {
     const { plan_account, mint, owner, planTokenAccount } = await createPlan();
-    const { subscriptionAccount, payerTokenAccount } = await createSubscription(
-      {
-        owner,
-        mint,
-        planAccount: plan_account,
-        planTokenAccount,
-      }
-    );
-    await new Promise((resolve) => setTimeout(resolve, 1000));
-    const random = anchor.web3.Keypair.generate();
-    const airdropTx = await connection.requestAirdrop(
-      random.publicKey,
-      2000000000
-    );
-    await connection.confirmTransaction(airdropTx);
-    const deployerTokenAccount = await getOrCreateAssociatedTokenAccount(
-      connection,
-      deployer,
-      mint,
-      deployer.publicKey
-    );
-    const ownerTokenAccount = await getOrCreateAssociatedTokenAccount(
-      connection,
+    const { subscriptionAccount } = await createSubscription({
       owner,
       mint,
-      owner.publicKey,
-      true
-    );
+      planAccount: plan_account,
+      planTokenAccount,
+    });
+    await program.methods
+      .updatePlan({
+        price: new anchor.BN(20 * 10 ** 9),
+        termInSeconds: null,
+        retryLimit: null,
+        retryIntervalSeconds: null,
+        graceSeconds: null,
+        keeperReward: null,
+      })
+      .accounts({
+        payer: owner.publicKey,
+        planAccount: plan_account,
+      })
+      .signers([owner])
+      .rpc();
+    const planData = await program.account.plan.fetch(plan_account);
+    expect(planData.version).to.eq(1);
+    expect(planData.price.toNumber()).to.eq(20 * 10 ** 9);
+    const data = await program.account.subscription.fetch(subscriptionAccount);
+    expect(data.planVersion).to.eq(0);
+    expect(data.termPrice.toNumber()).to.eq(10 * 10 ** 9);
 
+    const random = anchor.web3.Keypair.generate();
     await expect(
       program.methods
-        .chargeSubscription()
+        .updatePlan({
+          price: new anchor.BN(1),
+          termInSeconds: null,
+          retryLimit: null,
+          retryIntervalSeconds: null,
+          graceSeconds: null,
+          keeperReward: null,
+        })
         .accounts({
           payer: random.publicKey,
           planAccount: plan_account,
-          subscriptionAccount,
-          planTokenAccount,
-          subscriberTokenAccount: payerTokenAccount.address,
-          deployerTokenAccount: deployerTokenAccount.address,
-          ownerTokenAccount: ownerTokenAccount.address,
         })
         .signers([random])
         .rpc()
     ).to.eventually.rejected;
   });
 
-  it("Charges subscription after one second", async () => {
-    const { plan_account, mint, owner, planTokenAccount } = await createPlan({
-      termInSeconds: 1,
-    });
-    const { subscriptionAccount, payerTokenAccount } = await createSubscription(
-      {
+  it("Rejects a zero term or a zero price with their own error codes", async () => {
+    const { plan_account, owner } = await createPlan();
+    const updatePlan = (price: number | null, termInSeconds: number | null) =>
+      program.methods
+        .updatePlan({
+          price: price === null ? null : new anchor.BN(price),
+          termInSeconds:
+            termInSeconds === null ? null : new anchor.BN(termInSeconds),
+          retryLimit: null,
+          retryIntervalSeconds: null,
+          graceSeconds: null,
+          keeperReward: null,
+        })
+        .accounts({
+          payer: owner.publicKey,
+          planAccount: plan_account,
+        })
+        .signers([owner])
+        .rpc();
+    await expect(updatePlan(null, 0)).to.eventually.rejectedWith("InvalidTerm");
+    await expect(updatePlan(0, null)).to.eventually.rejectedWith(
+      "InvalidPrice"
+    );
+    // a metered plan can be free apart from its usage;
+    await program.methods
+      .updatePlan({
+        price: new anchor.BN(0),
+        termInSeconds: null,
+        retryLimit: null,
+        retryIntervalSeconds: null,
+        graceSeconds: null,
+        keeperReward: null,
+        unitPrice: new anchor.BN(1),
+      })
+      .accounts({
+        payer: owner.publicKey,
+        planAccount: plan_account,
+      })
+      .signers([owner])
+      .rpc();
+    const plan = await program.account.plan.fetch(plan_account);
+    expect(plan.price.toNumber()).to.eq(0);
+  });
+
+  it("Rejects a grace period too long to schedule", async () => {
+    const { plan_account, owner } = await createPlan();
+    await expect(
+      program.methods
+        .updatePlan({
+          price: null,
+          termInSeconds: null,
+          retryLimit: null,
+          retryIntervalSeconds: null,
+          graceSeconds: new anchor.BN("18446744073709551615"),
+          keeperReward: null,
+        })
+        .accounts({
+          payer: owner.publicKey,
+          planAccount: plan_account,
+        })
+        .signers([owner])
+        .rpc()
+    ).to.eventually.rejectedWith("InvalidDuration");
+  });
+
+  it("Retires a plan and closes it", async () => {
+    const { plan_account, mint, owner, planTokenAccount } = await createPlan();
+    await program.methods
+      .updatePlanState({ state: { closedToNewSubscribers: {} } })
+      .accounts({
+        payer: owner.publicKey,
+        planAccount: plan_account,
+      })
+      .signers([owner])
+      .rpc();
+    await expect(
+      createSubscription({
         owner,
         mint,
         planAccount: plan_account,
         planTokenAccount,
-      }
-    );
-    await new Promise((resolve) => setTimeout(resolve, 1000));
-    const random = anchor.web3.Keypair.generate();
-    const airdropTx = await connection.requestAirdrop(
-      random.publicKey,
-      2000000000
-    );
-    await connection.confirmTransaction(airdropTx);
+      })
+    ).to.eventually.rejected;
     const ownerTokenAccount = await getOrCreateAssociatedTokenAccount(
       connection,
       owner,
@@ -238,72 +473,93 @@ describe("subscription-program", () => {
       owner.publicKey,
       true
     );
-    const deployerAirdropTx = await connection.requestAirdrop(
-      deployer.publicKey,
-      2000000000
-    );
-    await connection.confirmTransaction(deployerAirdropTx);
-    const deployTokenAccount = await getOrCreateAssociatedTokenAccount(
-      connection,
-      deployer,
-      mint,
-      deployer.publicKey
-    );
     await program.methods
-      .chargeSubscription()
+      .closePlan()
       .accounts({
-        payer: random.publicKey,
+        mintAccount: mint,
+        payer: owner.publicKey,
         planAccount: plan_account,
-        subscriptionAccount,
         planTokenAccount,
-        subscriberTokenAccount: payerTokenAccount.address,
         ownerTokenAccount: ownerTokenAccount.address,
-        deployerTokenAccount: deployTokenAccount.address,
       })
-      .signers([random])
+      .signers([owner])
       .rpc();
+    await expect(program.account.plan.fetch(plan_account)).to.eventually
+      .rejected;
+  });
 
-    const escrowBalance = await connection.getTokenAccountBalance(
-      planTokenAccount
-    );
-    expect(escrowBalance.value.uiAmount).to.eq(10);
-    const ownerBalance = await connection.getTokenAccountBalance(
-      ownerTokenAccount.address
-    );
-    expect(ownerBalance.value.uiAmount).to.eq(9.7);
-    const deployerBalance = await connection.getTokenAccountBalance(
-      deployTokenAccount.address
-    );
-    expect(deployerBalance.value.uiAmount).to.eq(0.3);
+  it("Creates accounts on the latest layout", async () => {
+    const { plan_account, mint, owner, planTokenAccount } = await createPlan();
+    const { subscriptionAccount } = await createSubscription({
+      owner,
+      mint,
+      planAccount: plan_account,
+      planTokenAccount,
+    });
+    const plan = await program.account.plan.fetch(plan_account);
+    expect(plan.layoutVersion).to.eq(2);
+    expect(plan.unmigratedSubscriptions.toNumber()).to.eq(0);
+    const data = await program.account.subscription.fetch(subscriptionAccount);
+    expect(data.layoutVersion).to.eq(2);
+    // nothing to migrate, and a subscription can't be passed off as a plan;
+    await expect(
+      program.methods
+        .migratePlan()
+        .accounts({ planAccount: plan_account, payer: owner.publicKey })
+        .signers([owner])
+        .rpc()
+    ).to.eventually.rejected;
+    await expect(
+      program.methods
+        .migratePlan()
+        .accounts({ planAccount: subscriptionAccount, payer: owner.publicKey })
+        .signers([owner])
+        .rpc()
+    ).to.eventually.rejected;
+    await expect(
+      program.methods
+        .migrateSubscription()
+        .accounts({
+          subscriptionAccount,
+          planAccount: plan_account,
+          payer: owner.publicKey,
+        })
+        .signers([owner])
+        .rpc()
+    ).to.eventually.rejectedWith("AlreadyMigrated");
+    // nothing was carried over from the original program, so there's nothing to wait on;
+    await program.methods
+      .releaseUnmigratedSubscriptions()
+      .accounts({ planAccount: plan_account, payer: owner.publicKey })
+      .signers([owner])
+      .rpc();
   });
 
-  it("Handles Past Due", async () => {
+  it("Starts a trial once per wallet", async () => {
     const { plan_account, mint, owner, planTokenAccount } = await createPlan({
-      termInSeconds: 1,
+      trialSeconds: 60,
     });
-    const { subscriptionAccount, payerTokenAccount } = await createSubscription(
-      {
+    const { subscriptionAccount, payer, payerTokenAccount } =
+      await createSubscription({
         owner,
         mint,
         planAccount: plan_account,
-        planTokenAccount: planTokenAccount,
-        amount: 15,
-      }
-    );
-    const random = anchor.web3.Keypair.generate();
-    const airdropTx = await connection.requestAirdrop(
-      random.publicKey,
-      2000000000
+        planTokenAccount,
+      });
+    const data = await program.account.subscription.fetch(subscriptionAccount);
+    expect(data.termPrice.toNumber()).to.eq(0);
+    const escrowBalance = await connection.getTokenAccountBalance(
+      planTokenAccount
     );
-    await connection.confirmTransaction(airdropTx);
-    const ownerTokenAccount = await getOrCreateAssociatedTokenAccount(
+    expect(escrowBalance.value.uiAmount).to.eq(0);
+
+    const planOwnerTokenAccount = await getOrCreateAssociatedTokenAccount(
       connection,
       owner,
       mint,
       owner.publicKey,
       true
     );
-    await new Promise((resolve) => setTimeout(resolve, 1000));
     const deployerTokenAccount = await getOrCreateAssociatedTokenAccount(
       connection,
       deployer,
@@ -311,35 +567,1741 @@ describe("subscription-program", () => {
       deployer.publicKey
     );
     await program.methods
-      .chargeSubscription()
+      .closeSubscription()
       .accounts({
-        payer: random.publicKey,
+        mintAccount: mint,
         planAccount: plan_account,
+        payerAuthority: payer.publicKey,
+        payer: payer.publicKey,
+        payerTokenAccount: payerTokenAccount.address,
         subscriptionAccount,
-        planTokenAccount: planTokenAccount,
-        subscriberTokenAccount: payerTokenAccount.address,
-        ownerTokenAccount: ownerTokenAccount.address,
+        planTokenAccount,
+        planOwnerTokenAccount: planOwnerTokenAccount.address,
         deployerTokenAccount: deployerTokenAccount.address,
+        protocolConfig,
       })
-      .signers([random])
+      .signers([payer])
       .rpc();
-
-    const data = await program.account.subscription.fetch(subscriptionAccount);
-    expect(!!data.state.pastDue).to.eq(true);
+    await createSubscription({
+      owner,
+      mint,
+      planAccount: plan_account,
+      planTokenAccount,
+      payer,
+    });
+    const data2 = await program.account.subscription.fetch(
+      subscriptionAccount
+    );
+    expect(data2.termPrice.toNumber()).to.eq(10 * 10 ** 9);
   });
 
-  it("Cancels & uncancels a subscription", async () => {
-    const { plan_account, mint, owner, planTokenAccount } = await createPlan();
-
-    const { subscriptionAccount, payerTokenAccount, payer } =
-      await createSubscription({
-        owner,
-        mint,
-        planAccount: plan_account,
-        planTokenAccount: planTokenAccount,
-      });
-
-    await program.methods
+  it("Escrows what arrives after a Token-2022 transfer fee", async () => {
+    const owner = anchor.web3.Keypair.generate();
+    const airdropTx = await connection.requestAirdrop(
+      owner.publicKey,
+      2000000000
+    );
+    await connection.confirmTransaction(airdropTx);
+    const mintKeypair = anchor.web3.Keypair.generate();
+    const mint = mintKeypair.publicKey;
+    const mintLen = getMintLen([ExtensionType.TransferFeeConfig]);
+    const lamports = await connection.getMinimumBalanceForRentExemption(
+      mintLen
+    );
+    // 1% transfer fee
+    await anchor.web3.sendAndConfirmTransaction(
+      connection,
+      new anchor.web3.Transaction().add(
+        anchor.web3.SystemProgram.createAccount({
+          fromPubkey: owner.publicKey,
+          newAccountPubkey: mint,
+          space: mintLen,
+          lamports,
+          programId: TOKEN_2022_PROGRAM_ID,
+        }),
+        createInitializeTransferFeeConfigInstruction(
+          mint,
+          owner.publicKey,
+          owner.publicKey,
+          100,
+          BigInt(10 ** 18),
+          TOKEN_2022_PROGRAM_ID
+        ),
+        createInitializeMintInstruction(
+          mint,
+          9,
+          owner.publicKey,
+          null,
+          TOKEN_2022_PROGRAM_ID
+        )
+      ),
+      [owner, mintKeypair]
+    );
+    const code = "test";
+    const [plan_account] = anchor.web3.PublicKey.findProgramAddressSync(
+      [
+        Buffer.from(anchor.utils.bytes.utf8.encode("plan")),
+        owner.publicKey.toBuffer(),
+        planCodeSeed(code),
+      ],
+      program.programId
+    );
+    const planTokenAccount = getAssociatedTokenAddressSync(
+      mint,
+      plan_account,
+      true,
+      TOKEN_2022_PROGRAM_ID
+    );
+    await program.methods
+      .createPlan({
+        code,
+        name: "",
+        metadataUri: "",
+        price: new anchor.BN(10 * 10 ** 9),
+        termInSeconds: new anchor.BN(30),
+        trialSeconds: new anchor.BN(0),
+        retryLimit: 0,
+        retryIntervalSeconds: new anchor.BN(0),
+        graceSeconds: new anchor.BN(0),
+        keeperReward: { none: {} },
+        usageAuthority: PublicKey.default,
+        unitPrice: new anchor.BN(0),
+        includedUnits: new anchor.BN(0),
+        cancelAuthority: { either: {} },
+        prepayDiscountBps: 0,
+        maxPauseSeconds: new anchor.BN(0),
+        maxPausesPerYear: 0,
+      })
+      .accounts({
+        payer: owner.publicKey,
+        planAccount: plan_account,
+        planTokenAccount,
+        mintAccount: mint,
+        tokenProgram: TOKEN_2022_PROGRAM_ID,
+      })
+      .signers([owner])
+      .rpc();
+    const { subscriptionAccount } = await createSubscription({
+      owner,
+      mint,
+      planAccount: plan_account,
+      planTokenAccount,
+      tokenProgram: TOKEN_2022_PROGRAM_ID,
+    });
+    const data = await program.account.subscription.fetch(subscriptionAccount);
+    expect(data.termPrice.toNumber()).to.eq(9.9 * 10 ** 9);
+  });
+
+  it("Prepays several terms at the plan's discount", async () => {
+    const { plan_account, mint, owner, planTokenAccount } = await createPlan({
+      termInSeconds: 2,
+      prepayDiscountBps: 1000,
+    });
+    const { subscriptionAccount, payer, payerTokenAccount } =
+      await createSubscription({
+        owner,
+        mint,
+        planAccount: plan_account,
+        planTokenAccount,
+        amount: 200,
+        prepaidTerms: 12,
+      });
+    const data = await program.account.subscription.fetch(subscriptionAccount);
+    expect(data.termPrice.toNumber()).to.eq(108 * 10 ** 9);
+    expect(data.termInSeconds.toNumber()).to.eq(24);
+    expect(data.paidThrough.toNumber()).to.eq(data.nextTermDate.toNumber());
+
+    await new Promise((resolve) => setTimeout(resolve, 3000));
+    const ownerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      owner,
+      mint,
+      owner.publicKey,
+      true
+    );
+    const deployerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      deployer,
+      mint,
+      deployer.publicKey
+    );
+    // the first monthly term is over but the prepaid period isn't;
+    await expect(
+      program.methods
+        .chargeSubscription()
+        .accounts({
+          mintAccount: mint,
+          payer: owner.publicKey,
+          planAccount: plan_account,
+          subscriptionAccount,
+          planTokenAccount,
+          subscriberTokenAccount: payerTokenAccount.address,
+          ownerTokenAccount: ownerTokenAccount.address,
+          keeperTokenAccount: ownerTokenAccount.address,
+          deployerTokenAccount: deployerTokenAccount.address,
+          protocolConfig,
+        })
+        .signers([owner])
+        .rpc()
+    ).to.eventually.rejected;
+
+    await program.methods
+      .closeSubscription()
+      .accounts({
+        mintAccount: mint,
+        planAccount: plan_account,
+        payerAuthority: payer.publicKey,
+        payer: payer.publicKey,
+        payerTokenAccount: payerTokenAccount.address,
+        subscriptionAccount,
+        planTokenAccount,
+        planOwnerTokenAccount: ownerTokenAccount.address,
+        deployerTokenAccount: deployerTokenAccount.address,
+        protocolConfig,
+      })
+      .signers([payer])
+      .rpc();
+    // most of the prepaid period was left, so most of the 108 comes back;
+    const payerBalance = await connection.getTokenAccountBalance(
+      payerTokenAccount.address
+    );
+    expect(payerBalance.value.uiAmount).to.be.greaterThan(170);
+    expect(payerBalance.value.uiAmount).to.be.lessThan(200);
+  });
+
+  it("Charges a gift from its vault and refunds the rest to the gifter", async () => {
+    const { plan_account, mint, owner, planTokenAccount } = await createPlan({
+      termInSeconds: 1,
+    });
+    const gifter = anchor.web3.Keypair.generate();
+    const recipient = anchor.web3.Keypair.generate();
+    const airdropTx = await connection.requestAirdrop(
+      gifter.publicKey,
+      2000000000
+    );
+    await connection.confirmTransaction(airdropTx);
+    const gifterTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      gifter,
+      mint,
+      gifter.publicKey
+    );
+    await mintTo(
+      connection,
+      gifter,
+      mint,
+      gifterTokenAccount.address,
+      owner,
+      100 * 10 ** 9
+    );
+    const [subscriptionAccount] = anchor.web3.PublicKey.findProgramAddressSync(
+      [
+        Buffer.from(anchor.utils.bytes.utf8.encode("subscription")),
+        recipient.publicKey.toBuffer(),
+        plan_account.toBuffer(),
+      ],
+      program.programId
+    );
+    const [giftVault] = anchor.web3.PublicKey.findProgramAddressSync(
+      [
+        Buffer.from(anchor.utils.bytes.utf8.encode("gift_vault")),
+        subscriptionAccount.toBuffer(),
+      ],
+      program.programId
+    );
+    await program.methods
+      .giftSubscription({ terms: 3 })
+      .accounts({
+        subscriptionAccount,
+        giftVault,
+        planAccount: plan_account,
+        planTokenAccount,
+        payerTokenAccount: gifterTokenAccount.address,
+        mintAccount: mint,
+        beneficiary: recipient.publicKey,
+        payer: gifter.publicKey,
+      })
+      .signers([gifter, recipient])
+      .rpc();
+    let vaultBalance = await connection.getTokenAccountBalance(giftVault);
+    expect(vaultBalance.value.uiAmount).to.eq(20);
+
+    await new Promise((resolve) => setTimeout(resolve, 1000));
+    const ownerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      owner,
+      mint,
+      owner.publicKey,
+      true
+    );
+    const deployerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      deployer,
+      mint,
+      deployer.publicKey
+    );
+    await program.methods
+      .chargeSubscription()
+      .accounts({
+        mintAccount: mint,
+        payer: owner.publicKey,
+        planAccount: plan_account,
+        subscriptionAccount,
+        planTokenAccount,
+        subscriberTokenAccount: giftVault,
+        ownerTokenAccount: ownerTokenAccount.address,
+        keeperTokenAccount: ownerTokenAccount.address,
+        deployerTokenAccount: deployerTokenAccount.address,
+        protocolConfig,
+      })
+      .signers([owner])
+      .rpc();
+    vaultBalance = await connection.getTokenAccountBalance(giftVault);
+    expect(vaultBalance.value.uiAmount).to.eq(10);
+
+    await program.methods
+      .closeSubscription()
+      .accounts({
+        mintAccount: mint,
+        planAccount: plan_account,
+        giftVault,
+        payerAuthority: gifter.publicKey,
+        payer: gifter.publicKey,
+        payerTokenAccount: gifterTokenAccount.address,
+        subscriptionAccount,
+        planTokenAccount,
+        planOwnerTokenAccount: ownerTokenAccount.address,
+        deployerTokenAccount: deployerTokenAccount.address,
+        protocolConfig,
+      })
+      .signers([gifter])
+      .rpc();
+    expect(await connection.getAccountInfo(giftVault)).to.eq(null);
+    // 30 paid up front, the unstarted term back plus whatever was left of the current one;
+    const gifterBalance = await connection.getTokenAccountBalance(
+      gifterTokenAccount.address
+    );
+    expect(gifterBalance.value.uiAmount).to.be.at.least(80);
+  });
+
+  it("Ends a gift that has run out instead of putting it past due", async () => {
+    const { plan_account, mint, owner, planTokenAccount } = await createPlan({
+      termInSeconds: 1,
+    });
+    const gifter = anchor.web3.Keypair.generate();
+    const recipient = anchor.web3.Keypair.generate();
+    const airdropTx = await connection.requestAirdrop(
+      gifter.publicKey,
+      2000000000
+    );
+    await connection.confirmTransaction(airdropTx);
+    const gifterTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      gifter,
+      mint,
+      gifter.publicKey
+    );
+    await mintTo(
+      connection,
+      gifter,
+      mint,
+      gifterTokenAccount.address,
+      owner,
+      100 * 10 ** 9
+    );
+    const [subscriptionAccount] = anchor.web3.PublicKey.findProgramAddressSync(
+      [
+        Buffer.from(anchor.utils.bytes.utf8.encode("subscription")),
+        recipient.publicKey.toBuffer(),
+        plan_account.toBuffer(),
+      ],
+      program.programId
+    );
+    const [giftVault] = anchor.web3.PublicKey.findProgramAddressSync(
+      [
+        Buffer.from(anchor.utils.bytes.utf8.encode("gift_vault")),
+        subscriptionAccount.toBuffer(),
+      ],
+      program.programId
+    );
+    await program.methods
+      .giftSubscription({ terms: 1 })
+      .accounts({
+        subscriptionAccount,
+        giftVault,
+        planAccount: plan_account,
+        planTokenAccount,
+        payerTokenAccount: gifterTokenAccount.address,
+        mintAccount: mint,
+        beneficiary: recipient.publicKey,
+        payer: gifter.publicKey,
+      })
+      .signers([gifter, recipient])
+      .rpc();
+
+    await new Promise((resolve) => setTimeout(resolve, 1000));
+    const ownerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      owner,
+      mint,
+      owner.publicKey,
+      true
+    );
+    const deployerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      deployer,
+      mint,
+      deployer.publicKey
+    );
+    await program.methods
+      .chargeSubscription()
+      .accounts({
+        mintAccount: mint,
+        payer: owner.publicKey,
+        planAccount: plan_account,
+        subscriptionAccount,
+        planTokenAccount,
+        subscriberTokenAccount: giftVault,
+        ownerTokenAccount: ownerTokenAccount.address,
+        keeperTokenAccount: ownerTokenAccount.address,
+        deployerTokenAccount: deployerTokenAccount.address,
+        protocolConfig,
+      })
+      .signers([owner])
+      .rpc();
+    const data = await program.account.subscription.fetch(subscriptionAccount);
+    expect(data.state).to.deep.eq({ cancelled: {} });
+    const plan = await program.account.plan.fetch(plan_account);
+    expect(plan.pastDueSubscriptions.toNumber()).to.eq(0);
+  });
+
+  it("Lets a sponsor pay for someone else's subscription", async () => {
+    const { plan_account, mint, owner, planTokenAccount } = await createPlan({
+      cancelAuthority: { payerAuthority: {} },
+    });
+    const beneficiary = anchor.web3.Keypair.generate();
+    const airdropTx = await connection.requestAirdrop(
+      beneficiary.publicKey,
+      2000000000
+    );
+    await connection.confirmTransaction(airdropTx);
+    const { subscriptionAccount, payer } = await createSubscription({
+      owner,
+      mint,
+      planAccount: plan_account,
+      planTokenAccount,
+      beneficiary,
+    });
+    const data = await program.account.subscription.fetch(subscriptionAccount);
+    expect(data.owner.toBase58()).to.eq(beneficiary.publicKey.toBase58());
+    expect(data.payerAuthority.toBase58()).to.eq(payer.publicKey.toBase58());
+
+    // the plan only lets whoever pays cancel;
+    await expect(
+      program.methods
+        .cancelSubscription()
+        .accounts({
+          subscriptionAccount,
+          planAccount: plan_account,
+          payer: beneficiary.publicKey,
+        })
+        .signers([beneficiary])
+        .rpc()
+    ).to.eventually.rejected;
+    await program.methods
+      .cancelSubscription()
+      .accounts({
+        subscriptionAccount,
+        planAccount: plan_account,
+        payer: payer.publicKey,
+      })
+      .signers([payer])
+      .rpc();
+    const cancelled = await program.account.subscription.fetch(
+      subscriptionAccount
+    );
+    expect(cancelled.state).to.deep.eq({ pendingCancellation: {} });
+  });
+
+  it("Prorates added seats now and removes seats at the next term", async () => {
+    const { plan_account, mint, owner, planTokenAccount } = await createPlan();
+    const { subscriptionAccount, payer, payerTokenAccount } =
+      await createSubscription({
+        owner,
+        mint,
+        planAccount: plan_account,
+        planTokenAccount,
+        quantity: 2,
+      });
+    const data = await program.account.subscription.fetch(subscriptionAccount);
+    expect(data.termPrice.toNumber()).to.eq(20 * 10 ** 9);
+
+    const updateQuantity = (quantity: number) =>
+      program.methods
+        .updateQuantity({ quantity })
+        .accounts({
+          subscriptionAccount,
+          planAccount: plan_account,
+          planTokenAccount,
+          payerTokenAccount: payerTokenAccount.address,
+          protocolConfig,
+          mintAccount: mint,
+          payer: payer.publicKey,
+        })
+        .signers([payer])
+        .rpc();
+    await updateQuantity(3);
+    const increased = await program.account.subscription.fetch(
+      subscriptionAccount
+    );
+    expect(increased.quantity).to.eq(3);
+    expect(increased.nextQuantity).to.eq(3);
+    // one more seat for at most the rest of the 30 second term;
+    const added = increased.termPrice.toNumber() - 20 * 10 ** 9;
+    expect(added).to.be.greaterThan(0);
+    expect(added).to.be.at.most(10 * 10 ** 9);
+
+    await updateQuantity(1);
+    const decreased = await program.account.subscription.fetch(
+      subscriptionAccount
+    );
+    expect(decreased.quantity).to.eq(3);
+    expect(decreased.nextQuantity).to.eq(1);
+    expect(decreased.termPrice.toNumber()).to.eq(
+      increased.termPrice.toNumber()
+    );
+    await expect(updateQuantity(0)).to.eventually.rejected;
+  });
+
+  it("Bills usage over the included units when the term ends", async () => {
+    const usageAuthority = anchor.web3.Keypair.generate();
+    const { plan_account, mint, owner, planTokenAccount } = await createPlan({
+      termInSeconds: 1,
+      usageAuthority: usageAuthority.publicKey,
+      unitPrice: 1,
+      includedUnits: 2,
+    });
+    const { subscriptionAccount, payerTokenAccount } = await createSubscription(
+      {
+        owner,
+        mint,
+        planAccount: plan_account,
+        planTokenAccount,
+      }
+    );
+    await program.methods
+      .reportUsage({ units: new anchor.BN(5) })
+      .accounts({
+        subscriptionAccount,
+        planAccount: plan_account,
+        usageAuthority: usageAuthority.publicKey,
+      })
+      .signers([usageAuthority])
+      .rpc();
+    await expect(
+      program.methods
+        .reportUsage({ units: new anchor.BN(5) })
+        .accounts({
+          subscriptionAccount,
+          planAccount: plan_account,
+          usageAuthority: owner.publicKey,
+        })
+        .signers([owner])
+        .rpc()
+    ).to.eventually.rejected;
+    // usage already reported this term keeps the pricing the term started on;
+    await program.methods
+      .updatePlan({
+        price: null,
+        termInSeconds: null,
+        retryLimit: null,
+        retryIntervalSeconds: null,
+        graceSeconds: null,
+        keeperReward: null,
+        usageAuthority: null,
+        unitPrice: new anchor.BN(5 * 10 ** 9),
+        includedUnits: new anchor.BN(0),
+      })
+      .accounts({
+        payer: owner.publicKey,
+        planAccount: plan_account,
+      })
+      .signers([owner])
+      .rpc();
+
+    await new Promise((resolve) => setTimeout(resolve, 1000));
+    const ownerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      owner,
+      mint,
+      owner.publicKey,
+      true
+    );
+    const deployerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      deployer,
+      mint,
+      deployer.publicKey
+    );
+    await program.methods
+      .chargeSubscription()
+      .accounts({
+        mintAccount: mint,
+        payer: owner.publicKey,
+        planAccount: plan_account,
+        subscriptionAccount,
+        planTokenAccount,
+        subscriberTokenAccount: payerTokenAccount.address,
+        ownerTokenAccount: ownerTokenAccount.address,
+        keeperTokenAccount: ownerTokenAccount.address,
+        deployerTokenAccount: deployerTokenAccount.address,
+        protocolConfig,
+      })
+      .signers([owner])
+      .rpc();
+
+    const data = await program.account.subscription.fetch(subscriptionAccount);
+    expect(data.usageUnits.toNumber()).to.eq(0);
+    // the new term starts on the new pricing;
+    expect(data.unitPrice.toNumber()).to.eq(5 * 10 ** 9);
+    expect(data.includedUnits.toNumber()).to.eq(0);
+    // 100 minted, 10 for each of two terms and 3 units of overage;
+    const payerBalance = await connection.getTokenAccountBalance(
+      payerTokenAccount.address
+    );
+    expect(payerBalance.value.uiAmount).to.eq(77);
+    const escrowBalance = await connection.getTokenAccountBalance(
+      planTokenAccount
+    );
+    expect(escrowBalance.value.uiAmount).to.eq(10);
+  });
+
+  it("Bills usage when the subscription is closed before the term ends", async () => {
+    const usageAuthority = anchor.web3.Keypair.generate();
+    const { plan_account, mint, owner, planTokenAccount } = await createPlan({
+      termInSeconds: 1000,
+      usageAuthority: usageAuthority.publicKey,
+      unitPrice: 1,
+      includedUnits: 2,
+    });
+    const { subscriptionAccount, payerTokenAccount, payer } =
+      await createSubscription({
+        owner,
+        mint,
+        planAccount: plan_account,
+        planTokenAccount,
+      });
+    await program.methods
+      .reportUsage({ units: new anchor.BN(5) })
+      .accounts({
+        subscriptionAccount,
+        planAccount: plan_account,
+        usageAuthority: usageAuthority.publicKey,
+      })
+      .signers([usageAuthority])
+      .rpc();
+    const planOwnerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      owner,
+      mint,
+      owner.publicKey,
+      true
+    );
+    const deployerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      deployer,
+      mint,
+      deployer.publicKey
+    );
+    await program.methods
+      .closeSubscription()
+      .accounts({
+        mintAccount: mint,
+        planAccount: plan_account,
+        payerAuthority: payer.publicKey,
+        payer: payer.publicKey,
+        payerTokenAccount: payerTokenAccount.address,
+        subscriptionAccount,
+        planTokenAccount,
+        planOwnerTokenAccount: planOwnerTokenAccount.address,
+        deployerTokenAccount: deployerTokenAccount.address,
+        protocolConfig,
+      })
+      .signers([payer])
+      .rpc();
+    // almost all of the term is refunded but the 3 units of overage are still paid for;
+    const payerBalance = await connection.getTokenAccountBalance(
+      payerTokenAccount.address
+    );
+    expect(payerBalance.value.uiAmount).to.be.closeTo(87, 0.05);
+  });
+
+  it("Rejects a coupon code with spaces in it", async () => {
+    const { plan_account, owner } = await createPlan();
+    const code = "HALF OFF";
+    const [couponAccount] = anchor.web3.PublicKey.findProgramAddressSync(
+      [
+        Buffer.from(anchor.utils.bytes.utf8.encode("coupon")),
+        plan_account.toBuffer(),
+        Buffer.from(anchor.utils.bytes.utf8.encode(code)),
+      ],
+      program.programId
+    );
+    await expect(
+      program.methods
+        .createCoupon({
+          code,
+          discount: { bps: [5000] },
+          durationTerms: 1,
+          maxRedemptions: 0,
+          expiresAt: new anchor.BN(0),
+        })
+        .accounts({
+          couponAccount,
+          planAccount: plan_account,
+          payer: owner.publicKey,
+        })
+        .signers([owner])
+        .rpc()
+    ).to.eventually.rejectedWith("InvalidCouponCode");
+  });
+
+  it("Charges the coupon price for the discounted terms", async () => {
+    const { plan_account, mint, owner, planTokenAccount } = await createPlan({
+      termInSeconds: 1,
+    });
+    const code = "HALFOFF";
+    const [couponAccount] = anchor.web3.PublicKey.findProgramAddressSync(
+      [
+        Buffer.from(anchor.utils.bytes.utf8.encode("coupon")),
+        plan_account.toBuffer(),
+        Buffer.from(anchor.utils.bytes.utf8.encode(code)),
+      ],
+      program.programId
+    );
+    await program.methods
+      .createCoupon({
+        code,
+        discount: { bps: [5000] },
+        durationTerms: 2,
+        maxRedemptions: 1,
+        expiresAt: new anchor.BN(0),
+      })
+      .accounts({
+        couponAccount,
+        planAccount: plan_account,
+        payer: owner.publicKey,
+      })
+      .signers([owner])
+      .rpc();
+    const { subscriptionAccount, payerTokenAccount } = await createSubscription(
+      {
+        owner,
+        mint,
+        planAccount: plan_account,
+        planTokenAccount,
+        coupon: couponAccount,
+      }
+    );
+    const data = await program.account.subscription.fetch(subscriptionAccount);
+    expect(data.termPrice.toNumber()).to.eq(5 * 10 ** 9);
+    expect(data.discountTermsRemaining).to.eq(1);
+
+    await new Promise((resolve) => setTimeout(resolve, 1000));
+    const ownerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      owner,
+      mint,
+      owner.publicKey,
+      true
+    );
+    const deployerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      deployer,
+      mint,
+      deployer.publicKey
+    );
+    await program.methods
+      .chargeSubscription()
+      .accounts({
+        mintAccount: mint,
+        payer: owner.publicKey,
+        planAccount: plan_account,
+        subscriptionAccount,
+        planTokenAccount,
+        subscriberTokenAccount: payerTokenAccount.address,
+        ownerTokenAccount: ownerTokenAccount.address,
+        keeperTokenAccount: ownerTokenAccount.address,
+        deployerTokenAccount: deployerTokenAccount.address,
+        protocolConfig,
+      })
+      .signers([owner])
+      .rpc();
+    const charged = await program.account.subscription.fetch(
+      subscriptionAccount
+    );
+    expect(charged.termPrice.toNumber()).to.eq(5 * 10 ** 9);
+    expect(charged.discountTermsRemaining).to.eq(0);
+    expect(charged.discount).to.deep.eq({ none: {} });
+
+    // the only redemption has been used;
+    await expect(
+      createSubscription({
+        owner,
+        mint,
+        planAccount: plan_account,
+        planTokenAccount,
+        coupon: couponAccount,
+      })
+    ).to.eventually.rejected;
+  });
+
+  it("Applies a coupon only once per subscriber", async () => {
+    const { plan_account, mint, owner, planTokenAccount } = await createPlan();
+    const code = "FIRSTMONTH";
+    const [couponAccount] = anchor.web3.PublicKey.findProgramAddressSync(
+      [
+        Buffer.from(anchor.utils.bytes.utf8.encode("coupon")),
+        plan_account.toBuffer(),
+        Buffer.from(anchor.utils.bytes.utf8.encode(code)),
+      ],
+      program.programId
+    );
+    await program.methods
+      .createCoupon({
+        code,
+        discount: { bps: [5000] },
+        durationTerms: 1,
+        maxRedemptions: 0,
+        expiresAt: new anchor.BN(0),
+      })
+      .accounts({
+        couponAccount,
+        planAccount: plan_account,
+        payer: owner.publicKey,
+      })
+      .signers([owner])
+      .rpc();
+    const { subscriptionAccount, payer } = await createSubscription({
+      owner,
+      mint,
+      planAccount: plan_account,
+      planTokenAccount,
+    });
+    const applyCoupon = () =>
+      program.methods
+        .applyCoupon()
+        .accounts({
+          subscriptionAccount,
+          planAccount: plan_account,
+          couponAccount,
+          couponRedemption: couponRedemptionAddress(
+            couponAccount,
+            payer.publicKey
+          ),
+          payer: payer.publicKey,
+        })
+        .signers([payer])
+        .rpc();
+    await applyCoupon();
+    const data = await program.account.subscription.fetch(subscriptionAccount);
+    expect(data.discount).to.deep.eq({ bps: [5000] });
+    expect(data.discountTermsRemaining).to.eq(1);
+    // unlimited redemptions still only means once for each subscriber;
+    await expect(applyCoupon()).to.eventually.rejectedWith(
+      "CouponAlreadyRedeemed"
+    );
+    const coupon = await program.account.coupon.fetch(couponAccount);
+    expect(coupon.redemptions).to.eq(1);
+  });
+
+  it("Defers a downgrade to a cheaper plan until the next term", async () => {
+    const { plan_account, mint, owner, planTokenAccount } = await createPlan();
+    const { subscriptionAccount, payer, payerTokenAccount } =
+      await createSubscription({
+        owner,
+        mint,
+        planAccount: plan_account,
+        planTokenAccount,
+      });
+    const code = "lite";
+    const [newPlanAccount] = anchor.web3.PublicKey.findProgramAddressSync(
+      [
+        Buffer.from(anchor.utils.bytes.utf8.encode("plan")),
+        owner.publicKey.toBuffer(),
+        planCodeSeed(code),
+      ],
+      program.programId
+    );
+    const newPlanTokenAccount = getAssociatedTokenAddressSync(
+      mint,
+      newPlanAccount,
+      true
+    );
+    await program.methods
+      .createPlan({
+        code,
+        name: "",
+        metadataUri: "",
+        price: new anchor.BN(5 * 10 ** 9),
+        termInSeconds: new anchor.BN(30),
+        trialSeconds: new anchor.BN(0),
+        retryLimit: 0,
+        retryIntervalSeconds: new anchor.BN(0),
+        graceSeconds: new anchor.BN(0),
+        keeperReward: { none: {} },
+        usageAuthority: PublicKey.default,
+        unitPrice: new anchor.BN(0),
+        includedUnits: new anchor.BN(0),
+        cancelAuthority: { either: {} },
+        prepayDiscountBps: 0,
+        maxPauseSeconds: new anchor.BN(0),
+        maxPausesPerYear: 0,
+      })
+      .accounts({
+        payer: owner.publicKey,
+        planAccount: newPlanAccount,
+        planTokenAccount: newPlanTokenAccount,
+        mintAccount: mint,
+      })
+      .signers([owner])
+      .rpc();
+    const ownerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      owner,
+      mint,
+      owner.publicKey,
+      true
+    );
+    const deployerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      deployer,
+      mint,
+      deployer.publicKey
+    );
+    const [newSubscriptionAccount] =
+      anchor.web3.PublicKey.findProgramAddressSync(
+        [
+          Buffer.from(anchor.utils.bytes.utf8.encode("subscription")),
+          payer.publicKey.toBuffer(),
+          newPlanAccount.toBuffer(),
+        ],
+        program.programId
+      );
+    const before = await program.account.subscription.fetch(
+      subscriptionAccount
+    );
+    await program.methods
+      .changePlan({ deferDowngrade: true })
+      .accounts({
+        subscriptionAccount,
+        newSubscriptionAccount,
+        planAccount: plan_account,
+        newPlanAccount,
+        planTokenAccount,
+        newPlanTokenAccount,
+        payerTokenAccount: payerTokenAccount.address,
+        ownerTokenAccount: ownerTokenAccount.address,
+        deployerTokenAccount: deployerTokenAccount.address,
+        protocolConfig,
+        mintAccount: mint,
+        payer: payer.publicKey,
+      })
+      .signers([payer])
+      .rpc();
+
+    const data = await program.account.subscription.fetch(
+      newSubscriptionAccount
+    );
+    expect(data.planAccount.toBase58()).to.eq(newPlanAccount.toBase58());
+    expect(data.nextTermDate.toNumber()).to.eq(before.nextTermDate.toNumber());
+    expect(data.termPrice.toNumber()).to.eq(10 * 10 ** 9);
+    const escrowBalance = await connection.getTokenAccountBalance(
+      newPlanTokenAccount
+    );
+    expect(escrowBalance.value.uiAmount).to.eq(10);
+    const oldSubscription = await connection.getAccountInfo(
+      subscriptionAccount
+    );
+    expect(oldSubscription).to.eq(null);
+    const planData = await program.account.plan.fetch(plan_account);
+    expect(planData.activeSubscriptions.toNumber()).to.eq(0);
+  });
+
+  it("Fails to charge before appropriate time", async () => {
+    const { plan_account, mint, owner, planTokenAccount } = await createPlan();
+    const { subscriptionAccount, payerTokenAccount } = await createSubscription(
+      {
+        owner,
+        mint,
+        planAccount: plan_account,
+        planTokenAccount,
+      }
+    );
+    await new Promise((resolve) => setTimeout(resolve, 1000));
+    const random = anchor.web3.Keypair.generate();
+    const airdropTx = await connection.requestAirdrop(
+      random.publicKey,
+      2000000000
+    );
+    await connection.confirmTransaction(airdropTx);
+    const deployerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      deployer,
+      mint,
+      deployer.publicKey
+    );
+    const ownerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      owner,
+      mint,
+      owner.publicKey,
+      true
+    );
+
+    await expect(
+      program.methods
+        .chargeSubscription()
+        .accounts({
+          mintAccount: mint,
+          payer: random.publicKey,
+          planAccount: plan_account,
+          subscriptionAccount,
+          planTokenAccount,
+          subscriberTokenAccount: payerTokenAccount.address,
+          deployerTokenAccount: deployerTokenAccount.address,
+          protocolConfig,
+          ownerTokenAccount: ownerTokenAccount.address,
+          keeperTokenAccount: ownerTokenAccount.address,
+        })
+        .signers([random])
+        .rpc()
+    ).to.eventually.rejected;
+  });
+
+  it("Charges subscription after one second", async () => {
+    const { plan_account, mint, owner, planTokenAccount } = await createPlan({
+      termInSeconds: 1,
+    });
+    const { subscriptionAccount, payerTokenAccount } = await createSubscription(
+      {
+        owner,
+        mint,
+        planAccount: plan_account,
+        planTokenAccount,
+      }
+    );
+    await new Promise((resolve) => setTimeout(resolve, 1000));
+    const random = anchor.web3.Keypair.generate();
+    const airdropTx = await connection.requestAirdrop(
+      random.publicKey,
+      2000000000
+    );
+    await connection.confirmTransaction(airdropTx);
+    const ownerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      owner,
+      mint,
+      owner.publicKey,
+      true
+    );
+    const deployerAirdropTx = await connection.requestAirdrop(
+      deployer.publicKey,
+      2000000000
+    );
+    await connection.confirmTransaction(deployerAirdropTx);
+    const deployTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      deployer,
+      mint,
+      deployer.publicKey
+    );
+    let chargedEvent;
+    const listener = program.addEventListener(
+      "SubscriptionCharged",
+      (event) => {
+        chargedEvent = event;
+      }
+    );
+    await program.methods
+      .chargeSubscription()
+      .accounts({
+        mintAccount: mint,
+        payer: random.publicKey,
+        planAccount: plan_account,
+        subscriptionAccount,
+        planTokenAccount,
+        subscriberTokenAccount: payerTokenAccount.address,
+        ownerTokenAccount: ownerTokenAccount.address,
+        keeperTokenAccount: ownerTokenAccount.address,
+        deployerTokenAccount: deployTokenAccount.address,
+        protocolConfig,
+      })
+      .signers([random])
+      .rpc();
+
+    await new Promise((resolve) => setTimeout(resolve, 1000));
+    await program.removeEventListener(listener);
+    expect(chargedEvent.termIndex).to.eq(1);
+    expect(chargedEvent.fee.toNumber()).to.eq(0.3 * 10 ** 9);
+
+    const escrowBalance = await connection.getTokenAccountBalance(
+      planTokenAccount
+    );
+    expect(escrowBalance.value.uiAmount).to.eq(10);
+    const ownerBalance = await connection.getTokenAccountBalance(
+      ownerTokenAccount.address
+    );
+    expect(ownerBalance.value.uiAmount).to.eq(9.7);
+    const deployerBalance = await connection.getTokenAccountBalance(
+      deployTokenAccount.address
+    );
+    expect(deployerBalance.value.uiAmount).to.eq(0.3);
+  });
+
+  it("Rewards the keeper out of the owner's share", async () => {
+    const { plan_account, mint, owner, planTokenAccount } = await createPlan({
+      termInSeconds: 1,
+      keeperReward: { fixed: [new anchor.BN(1 * 10 ** 9)] },
+    });
+    const { subscriptionAccount, payerTokenAccount } = await createSubscription(
+      {
+        owner,
+        mint,
+        planAccount: plan_account,
+        planTokenAccount,
+      }
+    );
+    const keeper = anchor.web3.Keypair.generate();
+    const airdropTx = await connection.requestAirdrop(
+      keeper.publicKey,
+      2000000000
+    );
+    await connection.confirmTransaction(airdropTx);
+    const keeperTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      keeper,
+      mint,
+      keeper.publicKey
+    );
+    const ownerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      owner,
+      mint,
+      owner.publicKey,
+      true
+    );
+    const deployerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      deployer,
+      mint,
+      deployer.publicKey
+    );
+    await new Promise((resolve) => setTimeout(resolve, 1000));
+    await program.methods
+      .chargeSubscription()
+      .accounts({
+        mintAccount: mint,
+        payer: keeper.publicKey,
+        planAccount: plan_account,
+        subscriptionAccount,
+        planTokenAccount,
+        subscriberTokenAccount: payerTokenAccount.address,
+        ownerTokenAccount: ownerTokenAccount.address,
+        deployerTokenAccount: deployerTokenAccount.address,
+        protocolConfig,
+        keeperTokenAccount: keeperTokenAccount.address,
+      })
+      .signers([keeper])
+      .rpc();
+    const keeperBalance = await connection.getTokenAccountBalance(
+      keeperTokenAccount.address
+    );
+    expect(keeperBalance.value.uiAmount).to.eq(1);
+    const ownerBalance = await connection.getTokenAccountBalance(
+      ownerTokenAccount.address
+    );
+    expect(ownerBalance.value.uiAmount).to.eq(8.7);
+  });
+
+  it("Rewards the keeper when a trial converts", async () => {
+    const { plan_account, mint, owner, planTokenAccount } = await createPlan({
+      termInSeconds: 3600,
+      trialSeconds: 1,
+      keeperReward: { fixed: [new anchor.BN(1 * 10 ** 9)] },
+    });
+    const { subscriptionAccount, payerTokenAccount } = await createSubscription(
+      {
+        owner,
+        mint,
+        planAccount: plan_account,
+        planTokenAccount,
+      }
+    );
+    const ownerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      owner,
+      mint,
+      owner.publicKey,
+      true
+    );
+    const deployerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      deployer,
+      mint,
+      deployer.publicKey
+    );
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+    await program.methods
+      .chargeSubscription()
+      .accounts({
+        mintAccount: mint,
+        payer: owner.publicKey,
+        planAccount: plan_account,
+        subscriptionAccount,
+        planTokenAccount,
+        subscriberTokenAccount: payerTokenAccount.address,
+        ownerTokenAccount: ownerTokenAccount.address,
+        deployerTokenAccount: deployerTokenAccount.address,
+        protocolConfig,
+        keeperTokenAccount: ownerTokenAccount.address,
+      })
+      .signers([owner])
+      .rpc();
+    // nothing was settled, so the reward comes out of the term just collected;
+    const ownerBalance = await connection.getTokenAccountBalance(
+      ownerTokenAccount.address
+    );
+    expect(ownerBalance.value.uiAmount).to.eq(1);
+    const data = await program.account.subscription.fetch(subscriptionAccount);
+    expect(data.termPrice.toNumber()).to.eq(9 * 10 ** 9);
+  });
+
+  it("Charges a batch of subscriptions with one payout", async () => {
+    const { plan_account, mint, owner, planTokenAccount } = await createPlan({
+      termInSeconds: 1,
+    });
+    const first = await createSubscription({
+      owner,
+      mint,
+      planAccount: plan_account,
+      planTokenAccount,
+    });
+    const second = await createSubscription({
+      owner,
+      mint,
+      planAccount: plan_account,
+      planTokenAccount,
+    });
+    const ownerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      owner,
+      mint,
+      owner.publicKey,
+      true
+    );
+    const deployerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      deployer,
+      mint,
+      deployer.publicKey
+    );
+    await new Promise((resolve) => setTimeout(resolve, 1000));
+    await program.methods
+      .chargeSubscriptionsBatch()
+      .accounts({
+        mintAccount: mint,
+        payer: owner.publicKey,
+        planAccount: plan_account,
+        planTokenAccount,
+        ownerTokenAccount: ownerTokenAccount.address,
+        deployerTokenAccount: deployerTokenAccount.address,
+        protocolConfig,
+        keeperTokenAccount: ownerTokenAccount.address,
+      })
+      .remainingAccounts(
+        [first, second].flatMap(({ subscriptionAccount, payerTokenAccount }) => [
+          { pubkey: subscriptionAccount, isSigner: false, isWritable: true },
+          {
+            pubkey: payerTokenAccount.address,
+            isSigner: false,
+            isWritable: true,
+          },
+        ])
+      )
+      .signers([owner])
+      .rpc();
+    const ownerBalance = await connection.getTokenAccountBalance(
+      ownerTokenAccount.address
+    );
+    expect(ownerBalance.value.uiAmount).to.eq(19.4);
+    const escrowBalance = await connection.getTokenAccountBalance(
+      planTokenAccount
+    );
+    expect(escrowBalance.value.uiAmount).to.eq(20);
+  });
+
+  it("Handles Past Due", async () => {
+    const { plan_account, mint, owner, planTokenAccount } = await createPlan({
+      termInSeconds: 1,
+    });
+    const { subscriptionAccount, payerTokenAccount } = await createSubscription(
+      {
+        owner,
+        mint,
+        planAccount: plan_account,
+        planTokenAccount: planTokenAccount,
+        amount: 15,
+      }
+    );
+    const random = anchor.web3.Keypair.generate();
+    const airdropTx = await connection.requestAirdrop(
+      random.publicKey,
+      2000000000
+    );
+    await connection.confirmTransaction(airdropTx);
+    const ownerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      owner,
+      mint,
+      owner.publicKey,
+      true
+    );
+    await new Promise((resolve) => setTimeout(resolve, 1000));
+    const deployerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      deployer,
+      mint,
+      deployer.publicKey
+    );
+    await program.methods
+      .chargeSubscription()
+      .accounts({
+        mintAccount: mint,
+        payer: random.publicKey,
+        planAccount: plan_account,
+        subscriptionAccount,
+        planTokenAccount: planTokenAccount,
+        subscriberTokenAccount: payerTokenAccount.address,
+        ownerTokenAccount: ownerTokenAccount.address,
+        keeperTokenAccount: ownerTokenAccount.address,
+        deployerTokenAccount: deployerTokenAccount.address,
+        protocolConfig,
+      })
+      .signers([random])
+      .rpc();
+
+    const data = await program.account.subscription.fetch(subscriptionAccount);
+    expect(!!data.state.pastDue).to.eq(true);
+    expect(data.retryCount).to.eq(0);
+    expect(data.pastDueSince.toNumber()).to.gt(0);
+    const ownerBalance = await connection.getTokenAccountBalance(
+      ownerTokenAccount.address
+    );
+    expect(ownerBalance.value.uiAmount).to.eq(9.7);
+
+    // no grace period configured, so the next attempt lapses the subscription;
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+    await program.methods
+      .chargeSubscription()
+      .accounts({
+        mintAccount: mint,
+        payer: random.publicKey,
+        planAccount: plan_account,
+        subscriptionAccount,
+        planTokenAccount: planTokenAccount,
+        subscriberTokenAccount: payerTokenAccount.address,
+        ownerTokenAccount: ownerTokenAccount.address,
+        keeperTokenAccount: ownerTokenAccount.address,
+        deployerTokenAccount: deployerTokenAccount.address,
+        protocolConfig,
+      })
+      .signers([random])
+      .rpc();
+    const data2 = await program.account.subscription.fetch(
+      subscriptionAccount
+    );
+    expect(!!data2.state.lapsed).to.eq(true);
+  });
+
+  it("Recovers a past due subscription on retry", async () => {
+    const { plan_account, mint, owner, planTokenAccount } = await createPlan({
+      termInSeconds: 1,
+      retryLimit: 3,
+      retryIntervalSeconds: 1,
+      graceSeconds: 60,
+    });
+    const { subscriptionAccount, payerTokenAccount } = await createSubscription(
+      {
+        owner,
+        mint,
+        planAccount: plan_account,
+        planTokenAccount: planTokenAccount,
+        amount: 15,
+      }
+    );
+    const random = anchor.web3.Keypair.generate();
+    const airdropTx = await connection.requestAirdrop(
+      random.publicKey,
+      2000000000
+    );
+    await connection.confirmTransaction(airdropTx);
+    const ownerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      owner,
+      mint,
+      owner.publicKey,
+      true
+    );
+    const deployerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      deployer,
+      mint,
+      deployer.publicKey
+    );
+    const charge = () =>
+      program.methods
+        .chargeSubscription()
+        .accounts({
+          mintAccount: mint,
+          payer: random.publicKey,
+          planAccount: plan_account,
+          subscriptionAccount,
+          planTokenAccount: planTokenAccount,
+          subscriberTokenAccount: payerTokenAccount.address,
+          ownerTokenAccount: ownerTokenAccount.address,
+          keeperTokenAccount: ownerTokenAccount.address,
+          deployerTokenAccount: deployerTokenAccount.address,
+          protocolConfig,
+        })
+        .signers([random])
+        .rpc();
+    await new Promise((resolve) => setTimeout(resolve, 1000));
+    await charge();
+    await mintTo(
+      connection,
+      owner,
+      mint,
+      payerTokenAccount.address,
+      owner,
+      10 * 10 ** 9
+    );
+    await new Promise((resolve) => setTimeout(resolve, 1000));
+    await charge();
+    const data = await program.account.subscription.fetch(subscriptionAccount);
+    expect(!!data.state.active).to.eq(true);
+    expect(data.retryCount).to.eq(0);
+  });
+
+  it("Waits for a refreshed delegation once the allowance runs out", async () => {
+    const { plan_account, mint, owner, planTokenAccount } = await createPlan({
+      termInSeconds: 1,
+      retryLimit: 3,
+      retryIntervalSeconds: 60,
+      graceSeconds: 60,
+    });
+    const { subscriptionAccount, payer, payerTokenAccount } =
+      await createSubscription({
+        owner,
+        mint,
+        planAccount: plan_account,
+        planTokenAccount: planTokenAccount,
+      });
+    const ownerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      owner,
+      mint,
+      owner.publicKey,
+      true
+    );
+    const deployerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      deployer,
+      mint,
+      deployer.publicKey
+    );
+    const refresh = (amount: number) =>
+      program.methods
+        .refreshDelegation({
+          delegationAmount: new anchor.BN(amount * 10 ** 9),
+        })
+        .accounts({
+          payer: payer.publicKey,
+          planAccount: plan_account,
+          subscriptionAccount,
+          payerTokenAccount: payerTokenAccount.address,
+        })
+        .signers([payer])
+        .rpc();
+    const charge = () =>
+      program.methods
+        .chargeSubscription()
+        .accounts({
+          mintAccount: mint,
+          payer: owner.publicKey,
+          planAccount: plan_account,
+          subscriptionAccount,
+          planTokenAccount: planTokenAccount,
+          subscriberTokenAccount: payerTokenAccount.address,
+          ownerTokenAccount: ownerTokenAccount.address,
+          keeperTokenAccount: ownerTokenAccount.address,
+          deployerTokenAccount: deployerTokenAccount.address,
+          protocolConfig,
+        })
+        .signers([owner])
+        .rpc();
+    // plenty in the account, but the subscription can only take 5 of the 10 due;
+    await refresh(5);
+    await new Promise((resolve) => setTimeout(resolve, 1000));
+    await charge();
+    const data = await program.account.subscription.fetch(subscriptionAccount);
+    expect(!!data.state.allowanceExhausted).to.eq(true);
+    expect(data.delegatedAllowance.toNumber()).to.eq(5 * 10 ** 9);
+    const plan = await program.account.plan.fetch(plan_account);
+    expect(plan.pastDueSubscriptions.toNumber()).to.eq(1);
+    // nothing to retry until the subscriber acts;
+    await expect(charge()).to.eventually.rejected;
+
+    // charged straight away once refreshed, without waiting for the retry interval;
+    await refresh(100);
+    await charge();
+    const data2 = await program.account.subscription.fetch(
+      subscriptionAccount
+    );
+    expect(!!data2.state.active).to.eq(true);
+    expect(data2.delegatedAllowance.toNumber()).to.eq(100 * 10 ** 9);
+    const plan2 = await program.account.plan.fetch(plan_account);
+    expect(plan2.pastDueSubscriptions.toNumber()).to.eq(0);
+    expect(plan2.activeSubscriptions.toNumber()).to.eq(1);
+  });
+
+  it("Cancels & uncancels a subscription", async () => {
+    const { plan_account, mint, owner, planTokenAccount } = await createPlan();
+
+    const { subscriptionAccount, payerTokenAccount, payer } =
+      await createSubscription({
+        owner,
+        mint,
+        planAccount: plan_account,
+        planTokenAccount: planTokenAccount,
+      });
+
+    await program.methods
+      .cancelSubscription()
+      .accounts({
+        planAccount: plan_account,
+        payer: payer.publicKey,
+        subscriptionAccount: subscriptionAccount,
+      })
+      .signers([payer])
+      .rpc();
+    const data = await program.account.subscription.fetch(subscriptionAccount);
+    expect(!!data.state.pendingCancellation).to.eq(true);
+    await program.methods
+      .uncancelSubscription()
+      .accounts({
+        planAccount: plan_account,
+        payer: payer.publicKey,
+        subscriptionAccount: subscriptionAccount,
+      })
+      .signers([payer])
+      .rpc();
+    const data2 = await program.account.subscription.fetch(subscriptionAccount);
+    expect(!!data2.state.active).to.eq(true);
+  });
+
+  it("Pauses and resumes with the rest of the term", async () => {
+    const { plan_account, mint, owner, planTokenAccount } = await createPlan({
+      termInSeconds: 3600,
+      maxPauseSeconds: 2,
+      maxPausesPerYear: 1,
+    });
+    const { subscriptionAccount, payer } = await createSubscription({
+      owner,
+      mint,
+      planAccount: plan_account,
+      planTokenAccount: planTokenAccount,
+    });
+    const random = anchor.web3.Keypair.generate();
+    const airdropTx = await connection.requestAirdrop(
+      random.publicKey,
+      2000000000
+    );
+    await connection.confirmTransaction(airdropTx);
+    const pause = () =>
+      program.methods
+        .pauseSubscription()
+        .accounts({
+          planAccount: plan_account,
+          payer: payer.publicKey,
+          subscriptionAccount,
+        })
+        .signers([payer])
+        .rpc();
+    const resume = (signer: Keypair) =>
+      program.methods
+        .resumeSubscription()
+        .accounts({
+          planAccount: plan_account,
+          payer: signer.publicKey,
+          subscriptionAccount,
+        })
+        .signers([signer])
+        .rpc();
+    const before = await program.account.subscription.fetch(
+      subscriptionAccount
+    );
+    await pause();
+    const paused = await program.account.subscription.fetch(
+      subscriptionAccount
+    );
+    expect(!!paused.state.paused).to.eq(true);
+    const plan = await program.account.plan.fetch(plan_account);
+    expect(plan.pausedSubscriptions.toNumber()).to.eq(1);
+    expect(plan.activeSubscriptions.toNumber()).to.eq(0);
+    // only the subscriber can resume until the longest pause is over;
+    await expect(resume(random)).to.eventually.rejected;
+
+    await new Promise((resolve) => setTimeout(resolve, 3000));
+    await resume(random);
+    const resumed = await program.account.subscription.fetch(
+      subscriptionAccount
+    );
+    expect(!!resumed.state.active).to.eq(true);
+    expect(resumed.nextTermDate.toNumber()).to.gte(
+      before.nextTermDate.toNumber() + 2
+    );
+    // one pause a year;
+    await expect(pause()).to.eventually.rejected;
+  });
+
+  it("Lets the plan owner cancel a subscription", async () => {
+    const { plan_account, mint, owner, planTokenAccount } = await createPlan({
+      termInSeconds: 3600,
+    });
+    const ownerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      owner,
+      mint,
+      owner.publicKey,
+      true
+    );
+    const deployerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      deployer,
+      mint,
+      deployer.publicKey
+    );
+    const merchantCancel = (
+      subscriptionAccount: PublicKey,
+      payer: Keypair,
+      payerTokenAccount: PublicKey,
+      mode: object
+    ) =>
+      program.methods
+        .merchantCancelSubscription({ mode, reasonCode: 7 })
+        .accounts({
+          subscriptionAccount,
+          planAccount: plan_account,
+          planTokenAccount,
+          payerTokenAccount,
+          ownerTokenAccount: ownerTokenAccount.address,
+          deployerTokenAccount: deployerTokenAccount.address,
+          protocolConfig,
+          giftVault: null,
+          mintAccount: mint,
+          payerAuthority: payer.publicKey,
+          payer: owner.publicKey,
+        })
+        .signers([owner])
+        .rpc();
+
+    // at the end of the term, which the subscriber can't undo;
+    const first = await createSubscription({
+      owner,
+      mint,
+      planAccount: plan_account,
+      planTokenAccount: planTokenAccount,
+    });
+    await expect(
+      program.methods
+        .merchantCancelSubscription({ mode: { endOfTerm: {} }, reasonCode: 7 })
+        .accounts({
+          subscriptionAccount: first.subscriptionAccount,
+          planAccount: plan_account,
+          planTokenAccount,
+          payerTokenAccount: first.payerTokenAccount.address,
+          ownerTokenAccount: ownerTokenAccount.address,
+          deployerTokenAccount: deployerTokenAccount.address,
+          protocolConfig,
+          giftVault: null,
+          mintAccount: mint,
+          payerAuthority: first.payer.publicKey,
+          payer: first.payer.publicKey,
+        })
+        .signers([first.payer])
+        .rpc()
+    ).to.eventually.rejected;
+    await merchantCancel(
+      first.subscriptionAccount,
+      first.payer,
+      first.payerTokenAccount.address,
+      { endOfTerm: {} }
+    );
+    const data = await program.account.subscription.fetch(
+      first.subscriptionAccount
+    );
+    expect(!!data.state.pendingCancellation).to.eq(true);
+    expect(data.merchantCancelled).to.eq(true);
+    await expect(
+      program.methods
+        .uncancelSubscription()
+        .accounts({
+          planAccount: plan_account,
+          payer: first.payer.publicKey,
+          subscriptionAccount: first.subscriptionAccount,
+        })
+        .signers([first.payer])
+        .rpc()
+    ).to.eventually.rejected;
+
+    // straight away with everything escrowed for the term refunded;
+    const second = await createSubscription({
+      owner,
+      mint,
+      planAccount: plan_account,
+      planTokenAccount: planTokenAccount,
+    });
+    await merchantCancel(
+      second.subscriptionAccount,
+      second.payer,
+      second.payerTokenAccount.address,
+      { fullRefund: {} }
+    );
+    const payerBalance = await connection.getTokenAccountBalance(
+      second.payerTokenAccount.address
+    );
+    expect(Number(payerBalance.value.amount)).to.eq(100 * 10 ** 9);
+    await expect(program.account.subscription.fetch(second.subscriptionAccount))
+      .to.eventually.rejected;
+    const plan = await program.account.plan.fetch(plan_account);
+    expect(plan.activeSubscriptions.toNumber()).to.eq(0);
+    expect(plan.pendingCancellationSubscriptions.toNumber()).to.eq(1);
+    expect(plan.totalRefunds.toNumber()).to.eq(10 * 10 ** 9);
+  });
+
+  it("Finalizes a cancellation at the end of the term", async () => {
+    const { plan_account, mint, owner, planTokenAccount } = await createPlan({
+      termInSeconds: 1,
+    });
+    const { subscriptionAccount, payerTokenAccount, payer } =
+      await createSubscription({
+        owner,
+        mint,
+        planAccount: plan_account,
+        planTokenAccount: planTokenAccount,
+      });
+    await program.methods
       .cancelSubscription()
       .accounts({
         planAccount: plan_account,
@@ -348,10 +2310,62 @@ describe("subscription-program", () => {
       })
       .signers([payer])
       .rpc();
-    const data = await program.account.subscription.fetch(subscriptionAccount);
-    expect(!!data.state.pendingCancellation).to.eq(true);
+    const ownerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      owner,
+      mint,
+      owner.publicKey,
+      true
+    );
+    const deployerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      deployer,
+      mint,
+      deployer.publicKey
+    );
+    await new Promise((resolve) => setTimeout(resolve, 2000));
     await program.methods
-      .uncancelSubscription()
+      .chargeSubscription()
+      .accounts({
+        mintAccount: mint,
+        payer: payer.publicKey,
+        planAccount: plan_account,
+        subscriptionAccount,
+        planTokenAccount,
+        subscriberTokenAccount: payerTokenAccount.address,
+        ownerTokenAccount: ownerTokenAccount.address,
+        keeperTokenAccount: ownerTokenAccount.address,
+        deployerTokenAccount: deployerTokenAccount.address,
+        protocolConfig,
+        payerAuthority: payer.publicKey,
+      })
+      .signers([payer])
+      .rpc();
+    const payerBalance = await connection.getTokenAccountBalance(
+      payerTokenAccount.address
+    );
+    expect(payerBalance.value.uiAmount).to.eq(90);
+    const ownerBalance = await connection.getTokenAccountBalance(
+      ownerTokenAccount.address
+    );
+    expect(ownerBalance.value.uiAmount).to.eq(9.7);
+    await expect(program.account.subscription.fetch(subscriptionAccount)).to
+      .eventually.rejected;
+  });
+
+  it("Settles an ended term when the subscription is closed before it's charged", async () => {
+    const { plan_account, mint, owner, planTokenAccount } = await createPlan({
+      termInSeconds: 1,
+    });
+    const { subscriptionAccount, payerTokenAccount, payer } =
+      await createSubscription({
+        owner,
+        mint,
+        planAccount: plan_account,
+        planTokenAccount: planTokenAccount,
+      });
+    await program.methods
+      .cancelSubscription()
       .accounts({
         planAccount: plan_account,
         payer: payer.publicKey,
@@ -359,8 +2373,125 @@ describe("subscription-program", () => {
       })
       .signers([payer])
       .rpc();
-    const data2 = await program.account.subscription.fetch(subscriptionAccount);
-    expect(!!data2.state.active).to.eq(true);
+    const planOwnerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      owner,
+      mint,
+      owner.publicKey,
+      true
+    );
+    const deployerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      deployer,
+      mint,
+      deployer.publicKey
+    );
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+    await program.methods
+      .closeSubscription()
+      .accounts({
+        mintAccount: mint,
+        planAccount: plan_account,
+        payerAuthority: payer.publicKey,
+        payer: payer.publicKey,
+        payerTokenAccount: payerTokenAccount.address,
+        subscriptionAccount,
+        planTokenAccount,
+        planOwnerTokenAccount: planOwnerTokenAccount.address,
+        deployerTokenAccount: deployerTokenAccount.address,
+        protocolConfig,
+      })
+      .signers([payer])
+      .rpc();
+    // nothing is left of the term to refund, so it's paid out like the finalizing charge would;
+    const payerBalance = await connection.getTokenAccountBalance(
+      payerTokenAccount.address
+    );
+    expect(payerBalance.value.uiAmount).to.eq(90);
+    const ownerBalance = await connection.getTokenAccountBalance(
+      planOwnerTokenAccount.address
+    );
+    expect(ownerBalance.value.uiAmount).to.eq(9.7);
+    const escrowBalance = await connection.getTokenAccountBalance(
+      planTokenAccount
+    );
+    expect(escrowBalance.value.uiAmount).to.eq(0);
+  });
+
+  it("Ends subscriptions on a sunset plan at the end of their term", async () => {
+    const { plan_account, mint, owner, planTokenAccount } = await createPlan({
+      termInSeconds: 1,
+    });
+    const { subscriptionAccount, payerTokenAccount, payer } =
+      await createSubscription({
+        owner,
+        mint,
+        planAccount: plan_account,
+        planTokenAccount: planTokenAccount,
+      });
+    await program.methods
+      .updatePlanState({ state: { sunset: {} } })
+      .accounts({
+        payer: owner.publicKey,
+        planAccount: plan_account,
+      })
+      .signers([owner])
+      .rpc();
+    const ownerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      owner,
+      mint,
+      owner.publicKey,
+      true
+    );
+    const deployerTokenAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      deployer,
+      mint,
+      deployer.publicKey
+    );
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+    await program.methods
+      .chargeSubscription()
+      .accounts({
+        mintAccount: mint,
+        payer: owner.publicKey,
+        planAccount: plan_account,
+        subscriptionAccount,
+        planTokenAccount,
+        subscriberTokenAccount: payerTokenAccount.address,
+        ownerTokenAccount: ownerTokenAccount.address,
+        keeperTokenAccount: ownerTokenAccount.address,
+        deployerTokenAccount: deployerTokenAccount.address,
+        protocolConfig,
+        payerAuthority: payer.publicKey,
+      })
+      .signers([owner])
+      .rpc();
+    // the last term is paid out and nothing new is charged;
+    const payerBalance = await connection.getTokenAccountBalance(
+      payerTokenAccount.address
+    );
+    expect(payerBalance.value.uiAmount).to.eq(90);
+    const ownerBalance = await connection.getTokenAccountBalance(
+      ownerTokenAccount.address
+    );
+    expect(ownerBalance.value.uiAmount).to.eq(9.7);
+    await expect(program.account.subscription.fetch(subscriptionAccount)).to
+      .eventually.rejected;
+    const planData = await program.account.plan.fetch(plan_account);
+    expect(planData.activeSubscriptions.toNumber()).to.eq(0);
+    await program.methods
+      .closePlan()
+      .accounts({
+        mintAccount: mint,
+        payer: owner.publicKey,
+        planAccount: plan_account,
+        planTokenAccount,
+        ownerTokenAccount: ownerTokenAccount.address,
+      })
+      .signers([owner])
+      .rpc();
   });
 
   it.only("Closes subscription and provides refund", async () => {
@@ -397,13 +2528,16 @@ describe("subscription-program", () => {
     const ret = await program.methods
       .closeSubscription()
       .accounts({
+        mintAccount: mint,
         planAccount: plan_account,
+        payerAuthority: payer.publicKey,
         payer: payer.publicKey,
         payerTokenAccount: payerTokenAccount.address,
         subscriptionAccount: subscriptionAccount,
         planTokenAccount,
         planOwnerTokenAccount: planOwnerTokenAccount.address,
         deployerTokenAccount: deployerTokenAccount.address,
+        protocolConfig,
       })
       .signers([payer])
       .rpc();
@@ -427,5 +2561,18 @@ describe("subscription-program", () => {
     expect(deployerBalance.value.uiAmount).to.gt(0);
     expect(program.account.subscription.fetch(subscriptionAccount)).to
       .eventually.rejected;
+
+    const planData = await program.account.plan.fetch(plan_account);
+    expect(planData.activeSubscriptions.toNumber()).to.eq(0);
+    expect(planData.lifetimeSubscriptions.toNumber()).to.eq(1);
+    expect(planData.totalRefunds.toNumber()).to.eq(
+      Number(payerBalance.value.amount)
+    );
+    expect(planData.lifetimeRevenue.toNumber()).to.eq(
+      Number(ownerBalance.value.amount)
+    );
+    expect(planData.totalFees.toNumber()).to.eq(
+      Number(deployerBalance.value.amount)
+    );
   });
 });